@@ -0,0 +1,208 @@
+use crate::book_service::{
+    CompactLevel, DeltaAction, ExchangeId, Level, LevelDelta, Summary, SummaryDelta,
+};
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, PartialEq)]
+pub enum DeltaError {
+    // A delta arrived before the first snapshot
+    MissingSnapshot,
+    // A delta applies to another summary than the last one, some deltas were missed
+    SequenceGap { last: u64, base: u64 },
+    InvalidIndex(u32),
+    MissingLevel(u32),
+    UnknownAction(i32),
+}
+
+impl Display for DeltaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeltaError::MissingSnapshot => write!(f, "delta received before the snapshot"),
+            DeltaError::SequenceGap { last, base } => write!(
+                f,
+                "delta applies to summary {base} but the last summary is {last}"
+            ),
+            DeltaError::InvalidIndex(index) => write!(f, "level index {index} is out of range"),
+            DeltaError::MissingLevel(index) => write!(f, "delta at index {index} has no level"),
+            DeltaError::UnknownAction(action) => write!(f, "unknown delta action {action}"),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+/// Reconstructs the summaries of a BookSummaryDeltas stream. After an error the book has
+/// to be resynchronised by opening a new stream, which starts with a snapshot
+#[derive(Debug, Default)]
+pub struct SummaryBook {
+    summary: Option<Summary>,
+}
+
+impl SummaryBook {
+    #[cfg(test)]
+    pub fn summary(&self) -> Option<&Summary> {
+        self.summary.as_ref()
+    }
+    /// Applies `delta` and returns the resulting summary, the book is cleared on errors
+    pub fn apply(&mut self, delta: SummaryDelta) -> Result<&Summary, DeltaError> {
+        // The book stays empty if the delta cannot be applied
+        let previous = self.summary.take();
+        let mut summary = if delta.snapshot {
+            Summary::default()
+        } else {
+            let summary = previous.ok_or(DeltaError::MissingSnapshot)?;
+            if summary.sequence != delta.base_sequence {
+                return Err(DeltaError::SequenceGap {
+                    last: summary.sequence,
+                    base: delta.base_sequence,
+                });
+            }
+            summary
+        };
+        apply_side(&mut summary.bids, delta.bids)?;
+        apply_side(&mut summary.asks, delta.asks)?;
+        for times in &delta.timestamps {
+            let exchange = exchange_name(times.exchange);
+            for level in summary.bids.iter_mut().chain(summary.asks.iter_mut()) {
+                if level.exchange == exchange {
+                    level.exchange_timestamp_us = times.exchange_timestamp_us;
+                    level.receive_timestamp_us = times.receive_timestamp_us;
+                }
+            }
+        }
+        summary.sequence = delta.sequence;
+        summary.publish_timestamp_us = delta.publish_timestamp_us;
+        summary.epoch = delta.epoch;
+        summary.spread = delta.spread;
+        summary.spread_exact = delta.spread_exact;
+        Ok(self.summary.insert(summary))
+    }
+}
+
+fn apply_side(levels: &mut Vec<Level>, deltas: Vec<LevelDelta>) -> Result<(), DeltaError> {
+    for delta in deltas {
+        let index = delta.index as usize;
+        let level = delta.level.map(level_of);
+        match DeltaAction::from_i32(delta.action) {
+            Some(DeltaAction::Insert) if index <= levels.len() => {
+                levels.insert(index, level.ok_or(DeltaError::MissingLevel(delta.index))?)
+            }
+            Some(DeltaAction::Update) if index < levels.len() => {
+                levels[index] = level.ok_or(DeltaError::MissingLevel(delta.index))?
+            }
+            Some(DeltaAction::Delete) if index < levels.len() => {
+                levels.remove(index);
+            }
+            Some(_) => return Err(DeltaError::InvalidIndex(delta.index)),
+            None => return Err(DeltaError::UnknownAction(delta.action)),
+        }
+    }
+    Ok(())
+}
+
+fn exchange_name(exchange: i32) -> &'static str {
+    match ExchangeId::from_i32(exchange) {
+        Some(ExchangeId::Binance) => "binance",
+        Some(ExchangeId::Bitstamp) => "bitstamp",
+        Some(ExchangeId::Kraken) => "kraken",
+        Some(ExchangeId::Coinbase) => "coinbase",
+        Some(ExchangeId::UnknownExchange) | None => "",
+    }
+}
+
+/// Level without timestamps, they are set from the timestamps of its exchange
+fn level_of(level: CompactLevel) -> Level {
+    Level {
+        exchange: exchange_name(level.exchange).to_string(),
+        price: level.price,
+        amount: level.amount,
+        converted: level.converted,
+        conversion_rate: level.conversion_rate,
+        stale: level.stale,
+        price_exact: level.price_exact,
+        amount_exact: level.amount_exact,
+        conversion_rate_exact: level.conversion_rate_exact,
+        ..Level::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::book_service::{
+        CompactLevel, DeltaAction, ExchangeId, ExchangeTimestamps, LevelDelta, SummaryDelta,
+    };
+    use crate::delta::{DeltaError, SummaryBook};
+
+    fn delta(action: DeltaAction, index: u32, price: f64) -> LevelDelta {
+        LevelDelta {
+            action: action as i32,
+            index,
+            level: (action != DeltaAction::Delete).then(|| CompactLevel {
+                exchange: ExchangeId::Kraken as i32,
+                price,
+                amount: 1.,
+                ..CompactLevel::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn summaries_are_reconstructed() {
+        let mut book = SummaryBook::default();
+        let snapshot = SummaryDelta {
+            sequence: 4,
+            snapshot: true,
+            bids: vec![
+                delta(DeltaAction::Insert, 0, 100.),
+                delta(DeltaAction::Insert, 1, 99.),
+            ],
+            ..SummaryDelta::default()
+        };
+        assert_eq!(
+            book.apply(SummaryDelta {
+                snapshot: false,
+                ..snapshot.clone()
+            }),
+            Err(DeltaError::MissingSnapshot)
+        );
+        assert_eq!(book.apply(snapshot.clone()).unwrap().bids.len(), 2);
+
+        let summary = book
+            .apply(SummaryDelta {
+                sequence: 6,
+                base_sequence: 4,
+                bids: vec![
+                    delta(DeltaAction::Insert, 1, 99.5),
+                    delta(DeltaAction::Delete, 2, 0.),
+                ],
+                asks: vec![delta(DeltaAction::Insert, 0, 101.)],
+                timestamps: vec![ExchangeTimestamps {
+                    exchange: ExchangeId::Kraken as i32,
+                    exchange_timestamp_us: 5,
+                    receive_timestamp_us: 7,
+                }],
+                ..SummaryDelta::default()
+            })
+            .unwrap();
+        // Timestamps apply to all levels of the exchange, also the unchanged ones
+        assert!(summary
+            .bids
+            .iter()
+            .all(|level| (level.exchange_timestamp_us, level.receive_timestamp_us) == (5, 7)));
+        let prices: Vec<f64> = summary.bids.iter().map(|level| level.price).collect();
+        assert_eq!(prices, vec![100., 99.5]);
+        assert_eq!(summary.asks[0].exchange, "kraken");
+        assert_eq!(summary.sequence, 6);
+
+        assert_eq!(
+            book.apply(SummaryDelta {
+                sequence: 8,
+                base_sequence: 7,
+                ..SummaryDelta::default()
+            }),
+            Err(DeltaError::SequenceGap { last: 6, base: 7 })
+        );
+        assert!(book.summary().is_none());
+        assert!(book.apply(snapshot).is_ok());
+    }
+}
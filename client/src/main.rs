@@ -1,9 +1,12 @@
 use crate::book_service::orderbook_aggregator_client::OrderbookAggregatorClient;
-use crate::book_service::Empty;
+use crate::book_service::SummaryRequest;
+use crate::delta::SummaryBook;
 use futures_util::StreamExt;
+use tonic::transport::Channel;
 pub mod book_service {
     tonic::include_proto!("orderbook");
 }
+mod delta;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -11,7 +14,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client = OrderbookAggregatorClient::connect("http://127.0.0.1:8080")
         .await
         .unwrap();
-    let summary_stream = client.book_summary(Empty {}).await?;
+    // Symbol may be omitted if the server aggregates a single symbol
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let symbol = args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .cloned()
+        .unwrap_or_default();
+    if args.iter().any(|arg| arg == "--delta") {
+        return stream_deltas(&mut client, symbol).await;
+    }
+    let summary_stream = client
+        .book_summary(SummaryRequest {
+            symbol,
+            ..Default::default()
+        })
+        .await?;
     println!("{summary_stream:?}");
     let mut stream = summary_stream.into_inner();
     while let Some(stuff) = stream.next().await {
@@ -19,3 +37,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     Ok(())
 }
+
+/// Prints the summaries reconstructed from the delta stream, resyncs on sequence gaps
+async fn stream_deltas(
+    client: &mut OrderbookAggregatorClient<Channel>,
+    symbol: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let mut stream = client
+            .book_summary_deltas(SummaryRequest {
+                symbol: symbol.clone(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        let mut book = SummaryBook::default();
+        let mut resync = false;
+        while let Some(delta) = stream.next().await {
+            match book.apply(delta?) {
+                Ok(summary) => println!("{summary:?}"),
+                Err(e) => {
+                    println!("Resyncing: {e}");
+                    resync = true;
+                    break;
+                }
+            }
+        }
+        if !resync {
+            return Ok(());
+        }
+    }
+}
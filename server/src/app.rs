@@ -0,0 +1,324 @@
+use crate::archive::{ArchiveConfig, SummaryArchive};
+use crate::defines::grpc_scheme::orderbook_aggregator_server::OrderbookAggregatorServer;
+use crate::defines::instrument::{InstrumentRegistry, Market};
+use crate::feed::{
+    BinanceBookMode, BitstampBookMode, CaptureConfig, CaptureRecorder, CaptureReplay, FeedConfig,
+    ReconnectPolicy, ReplayConfig, ReplaySpeed,
+};
+use crate::grpc_server::{BookSummaryService, LagPolicy, SummaryConfig};
+use crate::marketdata::ResumeWindow;
+use log::info;
+use log::LevelFilter::Info;
+use std::error::Error;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tonic::transport::Server;
+
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// Markets to aggregate, repeated or comma separated. A market is an instrument like
+    /// `BTC/USDT`, optionally followed by per exchange instruments, e.g. `BTC/USD;binance=BTC/USDT`.
+    /// Books in another quote asset are converted with the registry's conversion instruments
+    #[arg(
+        short,
+        long = "symbol",
+        value_delimiter = ',',
+        default_value = "BTC/USDT"
+    )]
+    symbols: Vec<String>,
+
+    /// JSON file with instruments added to or replacing the built-in ones
+    #[arg(long)]
+    instruments: Option<String>,
+
+    /// Levels of exchanges without a book update for this many milliseconds are flagged as
+    /// stale, 0 disables the check
+    #[arg(long, default_value_t = 5000)]
+    stale_after_ms: u64,
+
+    /// Minimum time between two summaries of a market, books received in between are
+    /// coalesced into the next summary. 0 publishes on every book
+    #[arg(long, default_value_t = 0)]
+    min_publish_interval_ms: u64,
+
+    /// Delay before the first reconnect attempt, doubled with every consecutive failure
+    #[arg(long, default_value_t = 500)]
+    reconnect_initial_delay_ms: u64,
+
+    /// Upper limit of the reconnect delay
+    #[arg(long, default_value_t = 30_000)]
+    reconnect_max_delay_ms: u64,
+
+    /// Consecutive connection failures after which a feed only retries every
+    /// `circuit_open_secs`, 0 disables the circuit breaker
+    #[arg(long, default_value_t = 10)]
+    circuit_failure_threshold: u32,
+
+    /// Delay between attempts while the circuit of a feed is open
+    #[arg(long, default_value_t = 300)]
+    circuit_open_secs: u64,
+
+    /// Levels per side kept of every exchange's book and the maximum depth clients can request
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u16).range(1..))]
+    depth: u16,
+
+    /// Length of the windows over which the summaries a slow client skipped are counted
+    #[arg(long, default_value_t = 10)]
+    lag_window_secs: u64,
+
+    /// Fraction of a window's summaries a client may skip before the window counts as lagging
+    #[arg(long, default_value_t = 0.5, value_parser = parse_ratio)]
+    max_skipped_ratio: f64,
+
+    /// Consecutive lagging windows after which a client is disconnected, 0 never disconnects
+    #[arg(long, default_value_t = 3)]
+    max_lagging_windows: u32,
+
+    /// Recent summaries kept per view for clients which resume their stream after
+    /// reconnecting, 0 disables resuming
+    #[arg(long, default_value_t = 1024)]
+    resume_buffer: usize,
+
+    /// Seconds a view's summaries are kept after its last client disconnected
+    #[arg(long, default_value_t = 60)]
+    resume_idle_secs: u64,
+
+    /// Views without clients whose summaries are kept at most, the longest idle ones are
+    /// dropped first
+    #[arg(long, default_value_t = 1024)]
+    resume_max_idle_views: usize,
+
+    /// Directory the websocket traffic of all feeds is captured to, capturing is disabled
+    /// if not set
+    #[arg(long)]
+    capture_dir: Option<String>,
+
+    /// Capture files are rotated after this many megabytes of uncompressed traffic
+    #[arg(long, default_value_t = 256)]
+    capture_max_file_mb: u64,
+
+    /// Capture files are rotated after this many seconds
+    #[arg(long, default_value_t = 3600)]
+    capture_rotate_secs: u64,
+
+    /// Oldest capture files are deleted when there are more, 0 keeps all
+    #[arg(long, default_value_t = 0)]
+    capture_max_files: usize,
+
+    /// Directory of a capture which the feeds replay instead of connecting to the exchanges.
+    /// The symbols and book modes have to match the ones the capture was recorded with
+    #[arg(long)]
+    replay_dir: Option<String>,
+
+    /// How fast the capture is replayed
+    #[arg(long, value_enum, default_value_t = ReplaySpeed::RealTime)]
+    replay_speed: ReplaySpeed,
+
+    /// Database file the published summaries are archived to, archiving is disabled if
+    /// not set
+    #[arg(long)]
+    archive_path: Option<String>,
+
+    /// Minimum time between two archived summaries of a market, 0 archives every summary
+    #[arg(long, default_value_t = 0)]
+    archive_interval_ms: u64,
+
+    /// Archived summaries older than this are deleted, 0 keeps all
+    #[arg(long, default_value_t = 0)]
+    archive_retention_secs: u64,
+
+    /// Address of socket to use
+    #[arg(short, long, default_value_t = String::from("127.0.0.1:8080"))]
+    address: String,
+
+    /// How the Binance book is obtained
+    #[arg(long, value_enum, default_value_t = BinanceBookMode::Partial)]
+    binance_mode: BinanceBookMode,
+
+    /// REST depth endpoint used to synchronise the Binance diff stream
+    #[arg(long, default_value_t = String::from("https://api.binance.com/api/v3/depth"))]
+    binance_snapshot_endpoint: String,
+
+    /// How the Bitstamp book is obtained
+    #[arg(long, value_enum, default_value_t = BitstampBookMode::Snapshot)]
+    bitstamp_mode: BitstampBookMode,
+
+    /// REST order book endpoint used to initialise the Bitstamp diff channel
+    #[arg(long, default_value_t = String::from("https://www.bitstamp.net/api/v2/order_book/"))]
+    bitstamp_snapshot_endpoint: String,
+}
+
+/// Runs the aggregation server until SIGTERM or Ctrl-C
+pub async fn run(args: Args) {
+    let Args {
+        symbols,
+        instruments,
+        stale_after_ms,
+        min_publish_interval_ms,
+        reconnect_initial_delay_ms,
+        reconnect_max_delay_ms,
+        circuit_failure_threshold,
+        circuit_open_secs,
+        depth,
+        lag_window_secs,
+        max_skipped_ratio,
+        max_lagging_windows,
+        resume_buffer,
+        resume_idle_secs,
+        resume_max_idle_views,
+        capture_dir,
+        capture_max_file_mb,
+        capture_rotate_secs,
+        capture_max_files,
+        replay_dir,
+        replay_speed,
+        archive_path,
+        archive_interval_ms,
+        archive_retention_secs,
+        address,
+        binance_mode,
+        binance_snapshot_endpoint,
+        bitstamp_mode,
+        bitstamp_snapshot_endpoint,
+    } = args;
+
+    env_logger::builder().filter_level(Info).init();
+    let address = address.parse().unwrap_or_else(|_| {
+        panic!(
+            "Provided address {} is not a a valid socket address",
+            address
+        )
+    });
+    let binance_snapshot_endpoint = binance_snapshot_endpoint.parse().unwrap_or_else(|_| {
+        panic!("Provided Binance snapshot endpoint {binance_snapshot_endpoint} is not a valid url")
+    });
+    let bitstamp_snapshot_endpoint = bitstamp_snapshot_endpoint.parse().unwrap_or_else(|_| {
+        panic!(
+            "Provided Bitstamp snapshot endpoint {bitstamp_snapshot_endpoint} is not a valid url"
+        )
+    });
+    let recorder = capture_dir.map(|directory| {
+        CaptureRecorder::start(CaptureConfig {
+            directory: directory.clone().into(),
+            max_file_bytes: capture_max_file_mb * 1024 * 1024,
+            max_file_age: Duration::from_secs(capture_rotate_secs),
+            max_files: capture_max_files,
+        })
+        .unwrap_or_else(|e| panic!("Failed to capture to {directory}: {e}"))
+    });
+    let archive = archive_path.map(|path| {
+        SummaryArchive::open(ArchiveConfig {
+            path: path.clone().into(),
+            min_interval: Duration::from_millis(archive_interval_ms),
+            depth: depth as usize,
+            retention: (archive_retention_secs > 0)
+                .then(|| Duration::from_secs(archive_retention_secs)),
+        })
+        .unwrap_or_else(|e| panic!("Failed to open summary archive {path}: {e}"))
+    });
+    let feed_config = FeedConfig {
+        binance_mode,
+        binance_snapshot_endpoint,
+        bitstamp_mode,
+        bitstamp_snapshot_endpoint,
+        reconnect: ReconnectPolicy {
+            initial_delay: Duration::from_millis(reconnect_initial_delay_ms),
+            max_delay: Duration::from_millis(reconnect_max_delay_ms),
+            failure_threshold: circuit_failure_threshold,
+            open_duration: Duration::from_secs(circuit_open_secs),
+            ..ReconnectPolicy::default()
+        },
+        depth: depth as usize,
+        recorder,
+        replay: replay_dir.map(|directory| {
+            CaptureReplay::new(ReplayConfig {
+                directory: directory.into(),
+                speed: replay_speed,
+            })
+        }),
+        book_timeout: true,
+    };
+    let mut registry = InstrumentRegistry::with_defaults();
+    if let Some(path) = instruments {
+        let json = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read instrument file {path}: {e}"));
+        registry
+            .extend_from_json(&json)
+            .unwrap_or_else(|e| panic!("Failed to load instrument file {path}: {e}"));
+    }
+    let markets: Vec<Market> = symbols
+        .iter()
+        .map(|symbol| {
+            Market::parse(symbol, &registry)
+                .unwrap_or_else(|e| panic!("Provided symbol {symbol} is not valid: {e}"))
+        })
+        .collect();
+    let summary_config = SummaryConfig {
+        stale_after: (stale_after_ms > 0).then(|| Duration::from_millis(stale_after_ms)),
+        min_publish_interval: Duration::from_millis(min_publish_interval_ms),
+        resume_window: ResumeWindow {
+            capacity: resume_buffer,
+            idle_retention: Duration::from_secs(resume_idle_secs),
+            max_idle_views: resume_max_idle_views,
+        },
+    };
+    let lag_policy = LagPolicy {
+        window: Duration::from_secs(lag_window_secs),
+        max_skipped_ratio,
+        max_lagging_windows,
+    };
+    let book_service = BookSummaryService::new(
+        &markets,
+        registry,
+        &feed_config,
+        summary_config,
+        lag_policy,
+        archive,
+    )
+    .await;
+    let lifecycle = book_service.lifecycle_handle();
+    let restart_lifecycle = lifecycle.clone();
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+        while hangup.recv().await.is_some() {
+            info!("Restarting feeds");
+            restart_lifecycle.restart_feeds().await;
+        }
+    });
+
+    if let Err(e) = Server::builder()
+        .add_service(OrderbookAggregatorServer::new(book_service))
+        .serve_with_shutdown(address, async move {
+            shutdown_signal().await;
+            info!("Shutting down");
+            // Streams end once their channels are closed, which lets the server finish
+            lifecycle.shutdown().await;
+        })
+        .await
+    {
+        println!(
+            "Failed to start gRPC server due to {} {}",
+            e,
+            e.source().map(|x| format!("{x}")).unwrap_or_default()
+        )
+    }
+}
+
+/// Parses a fraction between 0 and 1
+fn parse_ratio(value: &str) -> Result<f64, String> {
+    let ratio: f64 = value.parse().map_err(|e| format!("{e}"))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("{ratio} is not between 0 and 1"));
+    }
+    Ok(ratio)
+}
+
+/// Completes on SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+}
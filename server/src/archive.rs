@@ -0,0 +1,554 @@
+use crate::defines::grpc_scheme::Summary;
+use crate::helper::unix_time_us;
+use crate::marketdata::{same_levels, summary_of_levels, FullSummary, PricedLevel};
+use halfbrown::HashMap;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use prost::Message;
+use redb::{Database, TableDefinition};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// Encoded summaries keyed by market, publish time and sequence
+const SUMMARIES: TableDefinition<(&str, u64, u64), &[u8]> = TableDefinition::new("summaries");
+
+/// Where and how often published summaries are archived
+#[derive(Debug, Clone)]
+pub(crate) struct ArchiveConfig {
+    /// Database file, created if it does not exist
+    pub path: PathBuf,
+    /// Minimum time between two archived summaries of a market, only the latest levels
+    /// published in between are archived once it expired. 0 archives every summary
+    pub min_interval: Duration,
+    /// Levels per side of every exchange which are archived, queried views are exact up
+    /// to this depth
+    pub depth: usize,
+    /// Summaries published longer than this ago are deleted, all are kept if not set
+    pub retention: Option<Duration>,
+}
+
+enum Command {
+    Archive(String, Summary),
+    Close,
+}
+
+/// Stores the published summaries of all markets in an embedded database. Summaries are
+/// written by a dedicated thread so publishing never waits for the disk, they are dropped
+/// while the writer cannot keep up
+pub(crate) struct SummaryArchive {
+    database: Arc<Database>,
+    min_interval: Duration,
+    depth: usize,
+    sender: Mutex<Option<mpsc::SyncSender<Command>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    // Summaries dropped because the queue was full
+    dropped: AtomicU64,
+}
+
+impl SummaryArchive {
+    // Summaries queued for the writer before further ones are dropped
+    const QUEUE_CAPACITY: usize = 4 * 1024;
+    // Rows read per read transaction of a query, so that a slow client does not keep a
+    // transaction open
+    const QUERY_CHUNK: usize = 256;
+    // redb::Error is large but archive errors are rare and only logged
+    #[allow(clippy::result_large_err)]
+    pub fn open(config: ArchiveConfig) -> Result<Arc<Self>, redb::Error> {
+        let database = Arc::new(Database::create(&config.path)?);
+        // Queries of a new archive find an empty table
+        let transaction = database.begin_write()?;
+        transaction.open_table(SUMMARIES)?;
+        transaction.commit()?;
+        info!(target : "SummaryArchive", "Archiving summaries to {:?}", config.path);
+        let (sender, receiver) = mpsc::sync_channel(Self::QUEUE_CAPACITY);
+        let writer_database = database.clone();
+        let retention = config.retention;
+        let writer = std::thread::Builder::new()
+            .name("summary-archive".to_string())
+            .spawn(move || write_summaries(&writer_database, retention, receiver))
+            .map_err(redb::Error::Io)?;
+        Ok(Arc::new(Self {
+            database,
+            min_interval: config.min_interval,
+            depth: config.depth,
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+            dropped: AtomicU64::new(0),
+        }))
+    }
+    /// Archive of the summaries of `market`, its sequences continue after the ones
+    /// archived before
+    pub fn market(self: &Arc<Self>, market: &str) -> MarketArchive {
+        let sequence = self.last_sequence(market).unwrap_or_else(|e| {
+            error!(target : "SummaryArchive", "Failed to read the last sequence of {market}: {e}");
+            0
+        });
+        MarketArchive {
+            archive: self.clone(),
+            market: market.to_string(),
+            last: None,
+            pending: None,
+            sequence,
+        }
+    }
+    #[allow(clippy::result_large_err)]
+    fn last_sequence(&self, market: &str) -> Result<u64, redb::Error> {
+        let transaction = self.database.begin_read()?;
+        let table = transaction.open_table(SUMMARIES)?;
+        let last = table
+            .range((market, 0, 0)..=(market, u64::MAX, u64::MAX))?
+            .next_back()
+            .transpose()?;
+        Ok(last.map_or(0, |(key, _)| key.value().2))
+    }
+    /// Visits the summaries of `market` published from `from_us` until before `to_us`,
+    /// oldest first, until `visit` returns false. If `interval_us` is set only the last
+    /// summary of every interval is visited, intervals start at `from_us`. Summaries are
+    /// read in chunks, each with its own read transaction which is closed before visiting
+    #[allow(clippy::result_large_err)]
+    pub fn query(
+        &self,
+        market: &str,
+        from_us: u64,
+        to_us: u64,
+        interval_us: u64,
+        mut visit: impl FnMut(Summary) -> bool,
+    ) -> Result<(), redb::Error> {
+        // Encoded summary and its interval, summaries are only decoded once they are visited
+        let mut last: Option<(u64, Vec<u8>)> = None;
+        let decode = |encoded: &[u8]| {
+            Summary::decode(encoded)
+                .map_err(|e| redb::Error::Corrupted(format!("invalid archived summary: {e}")))
+        };
+        let mut start = Bound::Included((from_us, 0));
+        loop {
+            let chunk = self.read_chunk(market, start, to_us)?;
+            let Some((published_us, sequence, _)) = chunk.last() else {
+                break;
+            };
+            start = Bound::Excluded((*published_us, *sequence));
+            let complete = chunk.len() < Self::QUERY_CHUNK;
+            for (published_us, _, encoded) in chunk {
+                if interval_us == 0 {
+                    if !visit(decode(&encoded)?) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                let interval = (published_us - from_us) / interval_us;
+                if let Some((last_interval, last_encoded)) = &last {
+                    if *last_interval != interval && !visit(decode(last_encoded)?) {
+                        return Ok(());
+                    }
+                }
+                last = Some((interval, encoded));
+            }
+            if complete {
+                break;
+            }
+        }
+        if let Some((_, encoded)) = last {
+            visit(decode(&encoded)?);
+        }
+        Ok(())
+    }
+    /// Publish time, sequence and encoded summary of at most `QUERY_CHUNK` summaries of
+    /// `market` from `start` until before `to_us`
+    #[allow(clippy::result_large_err)]
+    fn read_chunk(
+        &self,
+        market: &str,
+        start: Bound<(u64, u64)>,
+        to_us: u64,
+    ) -> Result<Vec<(u64, u64, Vec<u8>)>, redb::Error> {
+        let transaction = self.database.begin_read()?;
+        let table = transaction.open_table(SUMMARIES)?;
+        let start = start.map(|(published_us, sequence)| (market, published_us, sequence));
+        let mut chunk = Vec::with_capacity(Self::QUERY_CHUNK);
+        for entry in table
+            .range((start, Bound::Excluded((market, to_us, 0))))?
+            .take(Self::QUERY_CHUNK)
+        {
+            let (key, value) = entry?;
+            let (_, published_us, sequence) = key.value();
+            chunk.push((published_us, sequence, value.value().to_vec()));
+        }
+        Ok(chunk)
+    }
+    fn archive(&self, market: &str, summary: Summary) {
+        if let Some(sender) = self.sender.lock().as_ref() {
+            // Disconnected only means that the writer stopped
+            if let Err(TrySendError::Full(_)) =
+                sender.try_send(Command::Archive(market.to_string(), summary))
+            {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!(target : "SummaryArchive", "Archive queue is full, dropping summaries");
+                }
+            }
+        }
+    }
+    /// Summaries dropped so far because the writer could not keep up
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+    /// Writes all pending summaries, later summaries are dropped
+    pub fn close(&self) {
+        if let Some(sender) = self.sender.lock().take() {
+            let _ = sender.send(Command::Close);
+        }
+        if let Some(writer) = self.writer.lock().take() {
+            let _ = writer.join();
+            let dropped = self.dropped();
+            if dropped > 0 {
+                warn!(target : "SummaryArchive", "{dropped} summaries were dropped");
+            }
+        }
+    }
+}
+
+impl Drop for SummaryArchive {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Archives the published summaries of a single market
+pub(crate) struct MarketArchive {
+    archive: Arc<SummaryArchive>,
+    market: String,
+    // Publish time and levels of the last archived summary
+    last: Option<(u64, Summary)>,
+    // Levels which changed within the minimum interval, archived once it expired
+    pending: Option<Summary>,
+    sequence: u64,
+}
+
+impl MarketArchive {
+    pub fn min_interval(&self) -> Duration {
+        self.archive.min_interval
+    }
+    /// Archives the levels of `full` up to the archive's depth unless they did not change.
+    /// Levels which changed within the minimum interval after the last archived summary
+    /// are kept until `flush_pending` or the next call after the interval archives them
+    pub fn archive(&mut self, full: &FullSummary) {
+        let depth = self.archive.depth;
+        let bounded = |levels: &[PricedLevel]| {
+            let mut per_exchange: HashMap<&str, usize> = HashMap::new();
+            levels
+                .iter()
+                .filter(|priced| {
+                    let count = per_exchange.entry(&priced.level.exchange).or_insert(0);
+                    *count += 1;
+                    *count <= depth
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let summary = summary_of_levels(bounded(&full.bids), bounded(&full.asks));
+        if self
+            .last
+            .as_ref()
+            .is_some_and(|(_, last)| same_levels(last, &summary))
+        {
+            self.pending = None;
+            return;
+        }
+        self.pending = Some(summary);
+        self.flush_pending();
+    }
+    /// Archives the pending levels if the minimum interval expired
+    pub fn flush_pending(&mut self) {
+        let now_us = unix_time_us();
+        let min_interval_us = self.archive.min_interval.as_micros() as u64;
+        let within_interval = self
+            .last
+            .as_ref()
+            .is_some_and(|(last_us, _)| now_us.saturating_sub(*last_us) < min_interval_us);
+        if within_interval {
+            return;
+        }
+        let Some(summary) = self.pending.take() else {
+            return;
+        };
+        self.sequence += 1;
+        let summary = Summary {
+            sequence: self.sequence,
+            publish_timestamp_us: now_us,
+            ..summary
+        };
+        self.archive.archive(&self.market, summary.clone());
+        self.last = Some((now_us, summary));
+    }
+}
+
+/// Writes the summaries of `receiver`, summaries which queued up while writing are
+/// written in a single transaction. Summaries of the written markets older than
+/// `retention` are deleted with them
+fn write_summaries(
+    database: &Database,
+    retention: Option<Duration>,
+    receiver: mpsc::Receiver<Command>,
+) {
+    while let Ok(first) = receiver.recv() {
+        let mut batch = Vec::new();
+        let mut closed = false;
+        for command in std::iter::once(first).chain(receiver.try_iter()) {
+            match command {
+                Command::Archive(market, summary) => batch.push((market, summary)),
+                Command::Close => closed = true,
+            }
+        }
+        let expired_before_us =
+            retention.map(|retention| unix_time_us().saturating_sub(retention.as_micros() as u64));
+        if let Err(e) = write_batch(database, &batch, expired_before_us) {
+            error!(target : "SummaryArchive", "Failed to archive {} summaries: {e}", batch.len());
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn write_batch(
+    database: &Database,
+    batch: &[(String, Summary)],
+    expired_before_us: Option<u64>,
+) -> Result<(), redb::Error> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let transaction = database.begin_write()?;
+    {
+        let mut table = transaction.open_table(SUMMARIES)?;
+        if let Some(expired_before_us) = expired_before_us {
+            let mut markets: Vec<&str> = batch.iter().map(|(market, _)| market.as_str()).collect();
+            markets.sort_unstable();
+            markets.dedup();
+            for market in markets {
+                table.retain_in((market, 0, 0)..(market, expired_before_us, 0), |_, _| false)?;
+            }
+        }
+        for (market, summary) in batch {
+            table.insert(
+                (
+                    market.as_str(),
+                    summary.publish_timestamp_us,
+                    summary.sequence,
+                ),
+                summary.encode_to_vec().as_slice(),
+            )?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::archive::{write_batch, ArchiveConfig, SummaryArchive};
+    use crate::defines::grpc_scheme::{Level, Summary};
+    use crate::marketdata::{FullSummary, PricedLevel};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn summary(sequence: u64, publish_timestamp_us: u64) -> Summary {
+        Summary {
+            sequence,
+            publish_timestamp_us,
+            bids: vec![Level {
+                exchange: "kraken".to_string(),
+                price: sequence as f64,
+                ..Level::default()
+            }],
+            ..Summary::default()
+        }
+    }
+
+    fn open(path: &Path, min_interval: Duration) -> std::sync::Arc<SummaryArchive> {
+        SummaryArchive::open(ArchiveConfig {
+            path: path.to_path_buf(),
+            min_interval,
+            depth: 1,
+            retention: Some(Duration::from_secs(3600)),
+        })
+        .unwrap()
+    }
+
+    fn sequences(archive: &SummaryArchive, market: &str) -> Vec<u64> {
+        let mut sequences = Vec::new();
+        archive
+            .query(market, 0, u64::MAX, 0, |summary| {
+                sequences.push(summary.sequence);
+                true
+            })
+            .unwrap();
+        sequences
+    }
+
+    #[test]
+    fn summaries_are_queried_by_time() {
+        let path = std::env::temp_dir().join(format!("archive-test-{}.redb", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let archive = open(&path, Duration::ZERO);
+        let batch: Vec<(String, Summary)> = [(1, 100), (2, 150), (3, 220), (4, 290), (5, 310)]
+            .into_iter()
+            .map(|(sequence, time_us)| ("BTC/USDT".to_string(), summary(sequence, time_us)))
+            .chain([("ETH/USDT".to_string(), summary(6, 200))])
+            .collect();
+        write_batch(&archive.database, &batch, None).unwrap();
+
+        let query = |from_us, to_us, interval_us| {
+            let mut sequences = Vec::new();
+            archive
+                .query("BTC/USDT", from_us, to_us, interval_us, |summary| {
+                    sequences.push(summary.sequence);
+                    true
+                })
+                .unwrap();
+            sequences
+        };
+        assert_eq!(query(150, 310, 0), vec![2, 3, 4]);
+        // Last summary of [100, 200), [200, 300) and [300, 400)
+        assert_eq!(query(100, 400, 100), vec![2, 4, 5]);
+        assert!(query(400, 500, 0).is_empty());
+
+        // Visiting stops once the visitor returns false
+        let mut visited = 0;
+        archive
+            .query("BTC/USDT", 0, u64::MAX, 0, |_| {
+                visited += 1;
+                false
+            })
+            .unwrap();
+        assert_eq!(visited, 1);
+
+        archive.close();
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn queries_read_in_chunks() {
+        let path = std::env::temp_dir().join(format!("archive-chunks-{}.redb", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let archive = open(&path, Duration::ZERO);
+        let count = SummaryArchive::QUERY_CHUNK as u64 * 2 + 10;
+        let batch: Vec<(String, Summary)> = (1..=count)
+            .map(|sequence| ("BTC/USDT".to_string(), summary(sequence, sequence * 10)))
+            .collect();
+        write_batch(&archive.database, &batch, None).unwrap();
+
+        assert_eq!(
+            sequences(&archive, "BTC/USDT"),
+            (1..=count).collect::<Vec<_>>()
+        );
+        // Intervals span chunks
+        let mut last = Vec::new();
+        archive
+            .query("BTC/USDT", 0, u64::MAX, 3_000, |summary| {
+                last.push(summary.sequence);
+                true
+            })
+            .unwrap();
+        assert_eq!(last, vec![299, 522]);
+
+        archive.close();
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn published_summaries_are_archived() {
+        let path = std::env::temp_dir().join(format!("archive-market-{}.redb", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let level = |exchange: &str, price: Decimal| PricedLevel {
+            price,
+            level: Level {
+                exchange: exchange.to_string(),
+                price_exact: price.to_string(),
+                ..Level::default()
+            },
+        };
+        let full = |best: Decimal| FullSummary {
+            bids: vec![
+                level("kraken", best),
+                level("kraken", best - dec!(1)),
+                level("binance", best - dec!(2)),
+            ],
+            asks: Vec::new(),
+        };
+
+        let archive = open(&path, Duration::ZERO);
+        // Published before the retention, it is deleted with the next write
+        write_batch(
+            &archive.database,
+            &[("BTC/USDT".to_string(), summary(1, 100))],
+            None,
+        )
+        .unwrap();
+        let mut market = archive.market("BTC/USDT");
+        market.archive(&full(dec!(100)));
+        // Levels did not change
+        market.archive(&full(dec!(100)));
+        market.archive(&full(dec!(101)));
+        archive.close();
+        market.archive(&full(dec!(102)));
+        assert_eq!(archive.dropped(), 0);
+        assert_eq!(sequences(&archive, "BTC/USDT"), vec![2, 3]);
+        let mut prices: Vec<Vec<String>> = Vec::new();
+        archive
+            .query("BTC/USDT", 0, u64::MAX, 0, |summary| {
+                prices.push(
+                    summary
+                        .bids
+                        .into_iter()
+                        .map(|level| level.price_exact)
+                        .collect(),
+                );
+                false
+            })
+            .unwrap();
+        // Only the best level of every exchange is archived
+        assert_eq!(prices, vec![vec!["100".to_string(), "98".to_string()]]);
+        drop(market);
+        drop(archive);
+
+        // Sequences continue after a restart, changes within the minimum interval are kept
+        // until it expired
+        let archive = open(&path, Duration::from_millis(50));
+        let mut market = archive.market("BTC/USDT");
+        market.archive(&full(dec!(103)));
+        market.archive(&full(dec!(104)));
+        // Reverted to the archived levels
+        market.archive(&full(dec!(103)));
+        std::thread::sleep(Duration::from_millis(60));
+        market.flush_pending();
+        market.archive(&full(dec!(105)));
+        // The last update before the market went quiet
+        market.archive(&full(dec!(106)));
+        market.flush_pending();
+        std::thread::sleep(Duration::from_millis(60));
+        market.flush_pending();
+        archive.close();
+        assert_eq!(sequences(&archive, "BTC/USDT"), vec![2, 3, 4, 5, 6]);
+        let mut best_bids = Vec::new();
+        archive
+            .query("BTC/USDT", 0, u64::MAX, 0, |summary| {
+                best_bids.push(summary.bids[0].price_exact.clone());
+                true
+            })
+            .unwrap();
+        assert_eq!(best_bids[2..], ["103", "105", "106"]);
+        drop(market);
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+use crate::defines::instrument::{Conversion, InstrumentRegistry, Market};
+use crate::defines::Exchange;
+use crate::feed::{
+    read_capture, CaptureRecord, FeedConfig, OrderbookFeedFactory, ReplayOutput, ReplayStream,
+};
+use crate::marketdata::{conversion_rate, BookAggregator};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub use crate::defines::error::{BacktestError, InstrumentError};
+pub use crate::defines::grpc_scheme::{Level, Summary};
+pub use crate::feed::{BinanceBookMode, BitstampBookMode};
+
+/// Capture and market of a backtest, the options correspond to the ones of the server
+#[derive(Debug, Clone)]
+pub struct BacktestConfig {
+    /// Directory of the capture files
+    pub capture_dir: PathBuf,
+    /// Aggregated market, e.g. `BTC/USDT` or `BTC/USD;binance=BTC/USDT`
+    pub market: String,
+    /// JSON instruments added to or replacing the built-in ones
+    pub instruments: Option<String>,
+    /// Levels per side kept of every exchange's book and of the summaries
+    pub depth: usize,
+    /// Levels of exchanges without a book update for this long are flagged as stale
+    pub stale_after: Option<Duration>,
+    /// Book modes have to match the ones the capture was recorded with
+    pub binance_mode: BinanceBookMode,
+    pub bitstamp_mode: BitstampBookMode,
+}
+
+impl Default for BacktestConfig {
+    fn default() -> Self {
+        Self {
+            capture_dir: PathBuf::from("capture"),
+            market: "BTC/USDT".to_string(),
+            instruments: None,
+            depth: 10,
+            stale_after: Some(Duration::from_secs(5)),
+            binance_mode: BinanceBookMode::default(),
+            bitstamp_mode: BitstampBookMode::default(),
+        }
+    }
+}
+
+/// What the books of a replayed stream are used for
+enum Source {
+    Book(Exchange),
+    Conversion(Conversion),
+}
+
+/// Replays a capture through the exchange APIs and the aggregator of the server without
+/// tasks or timers, so the same capture always yields the same summaries.
+///
+/// The simulated clock is the capture time of the records. Every book and disconnect of a
+/// market's exchange and every change of a conversion rate yields the summary the aggregator
+/// computes at the time of its record, with the summaries numbered from 1 and the simulated
+/// time as publish time.
+pub struct Backtest {
+    records: Box<dyn Iterator<Item = CaptureRecord>>,
+    streams: Vec<(Box<dyn ReplayStream>, Source)>,
+    aggregator: BookAggregator,
+    depth: usize,
+    // Simulated time in microseconds since the unix epoch
+    now_us: u64,
+    // Instant of the simulated time 0, the aggregator measures staleness with instants
+    origin: Instant,
+    sequence: u64,
+    // Summaries of the latest record which were not yielded yet
+    pending: VecDeque<Summary>,
+}
+
+impl Backtest {
+    pub fn new(config: &BacktestConfig) -> Result<Self, BacktestError> {
+        let records = read_capture(&config.capture_dir)?;
+        Self::with_records(config, records)
+    }
+    fn with_records(
+        config: &BacktestConfig,
+        records: impl Iterator<Item = CaptureRecord> + 'static,
+    ) -> Result<Self, BacktestError> {
+        let mut registry = InstrumentRegistry::with_defaults();
+        if let Some(json) = &config.instruments {
+            registry.extend_from_json(json)?;
+        }
+        let market = Market::parse(&config.market, &registry)?;
+        let feed_config = FeedConfig {
+            binance_mode: config.binance_mode,
+            bitstamp_mode: config.bitstamp_mode,
+            depth: config.depth,
+            ..FeedConfig::default()
+        };
+        let sources = market
+            .sources
+            .iter()
+            .map(|(exchange, instrument)| (*exchange, instrument, Source::Book(*exchange)));
+        let conversions = market.conversions.iter().map(|conversion| {
+            (
+                conversion.exchange,
+                &conversion.instrument,
+                Source::Conversion(conversion.clone()),
+            )
+        });
+        let streams = sources
+            .chain(conversions)
+            .filter_map(|(exchange, instrument, source)| {
+                OrderbookFeedFactory::create_replay_stream(exchange, &feed_config, instrument)
+                    .map(|stream| (stream, source))
+            })
+            .collect();
+        Ok(Self {
+            records: Box::new(records),
+            streams,
+            aggregator: BookAggregator::for_market(&market, config.stale_after),
+            depth: config.depth,
+            now_us: 0,
+            origin: Instant::now(),
+            sequence: 0,
+            pending: VecDeque::new(),
+        })
+    }
+    /// Current time of the simulated clock in microseconds since the unix epoch
+    pub fn now_us(&self) -> u64 {
+        self.now_us
+    }
+    /// Summary at `time_us` without replaying further records, e.g. to sample the book
+    /// between records
+    pub fn summary_at(&self, time_us: u64) -> Summary {
+        Summary {
+            publish_timestamp_us: time_us,
+            ..self
+                .aggregator
+                .make_summary_at(self.depth, self.instant_of(time_us))
+        }
+    }
+    fn instant_of(&self, time_us: u64) -> Instant {
+        self.origin + Duration::from_micros(time_us)
+    }
+    fn replay(&mut self, record: CaptureRecord) {
+        // Records of different feeds may be slightly out of order, the clock never goes back
+        self.now_us = self.now_us.max(record.time_us);
+        let mut outputs = Vec::new();
+        for (index, (stream, _)) in self.streams.iter_mut().enumerate() {
+            if stream.accepts(&record) {
+                outputs.extend(
+                    stream
+                        .handle(record.clone())
+                        .into_iter()
+                        .map(|output| (index, output)),
+                );
+            }
+        }
+        for (index, output) in outputs {
+            let changed = match (&self.streams[index].1, output) {
+                (Source::Book(exchange), ReplayOutput::Book(book)) => {
+                    let now = self.instant_of(self.now_us);
+                    self.aggregator.add_new_book_at(book, *exchange, now);
+                    true
+                }
+                (Source::Book(exchange), ReplayOutput::Disconnected) => {
+                    self.aggregator.remove_book(*exchange);
+                    true
+                }
+                (Source::Conversion(conversion), ReplayOutput::Book(book)) => {
+                    conversion_rate(conversion, &book)
+                        .is_some_and(|rate| self.aggregator.set_rate(&conversion.quote, rate))
+                }
+                (Source::Conversion(conversion), ReplayOutput::Disconnected) => {
+                    self.aggregator.remove_rate(&conversion.quote)
+                }
+                (_, ReplayOutput::Subscribed) => false,
+            };
+            if changed {
+                self.sequence += 1;
+                self.pending.push_back(Summary {
+                    sequence: self.sequence,
+                    ..self.summary_at(self.now_us)
+                });
+            }
+        }
+    }
+}
+
+impl Iterator for Backtest {
+    type Item = Summary;
+    fn next(&mut self) -> Option<Summary> {
+        loop {
+            if let Some(summary) = self.pending.pop_front() {
+                return Some(summary);
+            }
+            let record = self.records.next()?;
+            self.replay(record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backtest::{Backtest, BacktestConfig, Summary};
+    use crate::defines::Exchange;
+    use crate::feed::{CaptureEvent, CaptureRecord};
+    use std::time::Duration;
+
+    fn book(bid: &str, ask: &str) -> CaptureEvent {
+        CaptureEvent::Frame(format!(
+            r#"{{"stream":"btcusdt@depth5@100ms","data":{{"lastUpdateId":1,
+            "bids":[["{bid}","1.0"]],"asks":[["{ask}","1.0"]]}}}}"#
+        ))
+    }
+
+    fn records() -> Vec<CaptureRecord> {
+        let record = |time_us, exchange, symbol: &str, event| CaptureRecord {
+            time_us,
+            exchange,
+            symbol: symbol.to_string(),
+            event,
+        };
+        use Exchange::{Binance, Kraken};
+        vec![
+            record(
+                1,
+                Binance,
+                "btcusdt",
+                CaptureEvent::Subscribe("{}".to_string()),
+            ),
+            record(2, Binance, "btcusdt", CaptureEvent::Subscribed),
+            record(1_000, Binance, "btcusdt", book("100.0", "101.0")),
+            // Not part of the market
+            record(1_500, Kraken, "ETH/USDT", CaptureEvent::Subscribed),
+            record(2_000, Binance, "btcusdt", book("100.5", "101.0")),
+            record(
+                3_000_000,
+                Binance,
+                "btcusdt",
+                CaptureEvent::Disconnected("timeout".to_string()),
+            ),
+        ]
+    }
+
+    #[test]
+    fn backtest_is_deterministic() {
+        let config = BacktestConfig {
+            stale_after: Some(Duration::from_secs(1)),
+            ..BacktestConfig::default()
+        };
+        let backtest = || Backtest::with_records(&config, records().into_iter()).unwrap();
+        let summaries: Vec<Summary> = backtest().collect();
+        assert_eq!(summaries, backtest().collect::<Vec<_>>());
+
+        let bids: Vec<Vec<f64>> = summaries
+            .iter()
+            .map(|summary| summary.bids.iter().map(|level| level.price).collect())
+            .collect();
+        assert_eq!(bids, vec![vec![100.], vec![100.5], vec![]]);
+        assert_eq!(summaries[1].sequence, 2);
+        assert_eq!(summaries[1].publish_timestamp_us, 2_000);
+        assert_eq!(summaries[1].bids[0].receive_timestamp_us, 2_000);
+
+        // Staleness follows the simulated clock
+        let mut backtest = backtest();
+        backtest.nth(1);
+        assert_eq!(backtest.now_us(), 2_000);
+        assert!(!backtest.summary_at(999_999).bids[0].stale);
+        assert!(backtest.summary_at(1_002_000).bids[0].stale);
+    }
+}
@@ -6,10 +6,15 @@ use std::sync::Arc;
 #[async_trait]
 pub(crate) trait BookCallback: Send + Sync + 'static {
     async fn accept_book(&self, book: Orderbook, exchange: Exchange);
+    /// Called when the feed lost its connection, the last accepted book is outdated
+    async fn disconnected(&self, _exchange: Exchange) {}
 }
 #[async_trait]
 impl<T: BookCallback> BookCallback for Arc<T> {
     async fn accept_book(&self, book: Orderbook, exchange: Exchange) {
         BookCallback::accept_book(self.as_ref(), book, exchange).await
     }
+    async fn disconnected(&self, exchange: Exchange) {
+        BookCallback::disconnected(self.as_ref(), exchange).await
+    }
 }
@@ -1,19 +1,110 @@
 use crate::defines::json_parser::JSONError;
+use crate::defines::Exchange;
+use std::fmt::{Display, Formatter};
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use tokio_tungstenite::tungstenite::Error as TTError;
 
 pub type WebsocketResult<T> = Result<T, WebsocketError>;
 #[derive(Debug)]
 pub enum WebsocketError {
-    TungsteniteError(TTError),
+    TungsteniteError(Box<TTError>),
     Timeout,
     UnexpectedClosure,
     NoConfirmationReceived,
     JsonError(JSONError),
+    /// Locally maintained book does not match the checksum published by the exchange
+    ChecksumMismatch {
+        expected: u32,
+        computed: u32,
+    },
+    /// Incremental updates can no longer be applied to the local book
+    BookOutOfSync(&'static str),
+    /// Loading the REST book snapshot failed
+    SnapshotRequest(reqwest::Error),
+    /// Exchange refused the subscription, e.g. because the symbol is unknown
+    SubscriptionRejected(String),
+    /// Connection is open but stopped delivering messages or book updates
+    Inactive(&'static str),
 }
 
+impl WebsocketError {
+    /// False for errors which reconnecting will not resolve
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WebsocketError::SubscriptionRejected(_) => false,
+            WebsocketError::TungsteniteError(e) if matches!(e.as_ref(), TTError::Url(_)) => false,
+            _ => !self.http_status().is_some_and(is_fatal_status),
+        }
+    }
+    /// True if the exchange refused the connection because of too many requests. Binance
+    /// answers with 418 once the IP is banned for ignoring 429s
+    pub fn is_rate_limited(&self) -> bool {
+        self.http_status().is_some_and(|status| {
+            status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::IM_A_TEAPOT
+        })
+    }
+    /// Status of a rejected websocket handshake or snapshot request
+    fn http_status(&self) -> Option<StatusCode> {
+        match self {
+            WebsocketError::TungsteniteError(e) => match e.as_ref() {
+                TTError::Http(response) => Some(response.status()),
+                _ => None,
+            },
+            WebsocketError::SnapshotRequest(e) => e.status(),
+            _ => None,
+        }
+    }
+}
+
+/// Statuses which mean that the request itself is wrong. Other client errors such as rate
+/// limits, CDN rejections (403) or geo-blocking (451) pass or can be fixed by the operator
+fn is_fatal_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_REQUEST
+            | StatusCode::UNAUTHORIZED
+            | StatusCode::NOT_FOUND
+            | StatusCode::METHOD_NOT_ALLOWED
+            | StatusCode::GONE
+    )
+}
+
+impl Display for WebsocketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebsocketError::TungsteniteError(e) => write!(f, "websocket error: {e}"),
+            WebsocketError::Timeout => write!(f, "timeout"),
+            WebsocketError::UnexpectedClosure => write!(f, "connection closed unexpectedly"),
+            WebsocketError::NoConfirmationReceived => {
+                write!(f, "no subscription confirmation received")
+            }
+            WebsocketError::JsonError(e) => write!(f, "json error: {e}"),
+            WebsocketError::ChecksumMismatch { expected, computed } => {
+                write!(
+                    f,
+                    "checksum mismatch: expected {expected}, computed {computed}"
+                )
+            }
+            WebsocketError::BookOutOfSync(reason) => write!(f, "book out of sync: {reason}"),
+            WebsocketError::SnapshotRequest(e) => write!(f, "snapshot request failed: {e}"),
+            WebsocketError::SubscriptionRejected(reason) => {
+                write!(f, "subscription rejected: {reason}")
+            }
+            WebsocketError::Inactive(reason) => write!(f, "stream inactive: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for WebsocketError {}
+
 impl From<TTError> for WebsocketError {
     fn from(value: TTError) -> Self {
-        Self::TungsteniteError(value)
+        Self::TungsteniteError(Box::new(value))
+    }
+}
+impl From<reqwest::Error> for WebsocketError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::SnapshotRequest(value)
     }
 }
 impl From<JSONError> for WebsocketError {
@@ -21,3 +112,111 @@ impl From<JSONError> for WebsocketError {
         Self::JsonError(value)
     }
 }
+
+#[derive(Debug)]
+pub enum InstrumentError {
+    UnknownInstrument(String),
+    NotListed {
+        instrument: String,
+        exchange: Exchange,
+    },
+    InvalidMarket(String),
+    InvalidRegistry(JSONError),
+    /// Book is quoted in an asset which cannot be converted into the market's quote
+    QuoteMismatch {
+        market: String,
+        exchange: Exchange,
+        quote: String,
+    },
+}
+
+impl Display for InstrumentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InstrumentError::UnknownInstrument(name) => write!(f, "unknown instrument {name}"),
+            InstrumentError::NotListed {
+                instrument,
+                exchange,
+            } => write!(f, "{instrument} is not listed on {}", exchange.name()),
+            InstrumentError::InvalidMarket(spec) => write!(f, "invalid market {spec}"),
+            InstrumentError::InvalidRegistry(e) => write!(f, "invalid instrument registry: {e}"),
+            InstrumentError::QuoteMismatch {
+                market,
+                exchange,
+                quote,
+            } => write!(
+                f,
+                "{market} cannot aggregate the {quote} quoted book of {}, no conversion known",
+                exchange.name()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InstrumentError {}
+
+#[derive(Debug)]
+pub enum BacktestError {
+    Instrument(InstrumentError),
+    /// Capture cannot be read
+    Capture(std::io::Error),
+}
+
+impl Display for BacktestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BacktestError::Instrument(e) => write!(f, "{e}"),
+            BacktestError::Capture(e) => write!(f, "failed to read capture: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BacktestError {}
+
+impl From<InstrumentError> for BacktestError {
+    fn from(value: InstrumentError) -> Self {
+        Self::Instrument(value)
+    }
+}
+impl From<std::io::Error> for BacktestError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Capture(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::error::WebsocketError;
+    use tokio_tungstenite::tungstenite::http::Response;
+    use tokio_tungstenite::tungstenite::Error as TTError;
+
+    fn handshake_error(status: u16) -> WebsocketError {
+        let response = Response::builder().status(status).body(None).unwrap();
+        TTError::Http(response).into()
+    }
+
+    fn snapshot_error(status: u16) -> WebsocketError {
+        let response = Response::builder().status(status).body("").unwrap();
+        reqwest::Response::from(response)
+            .error_for_status()
+            .unwrap_err()
+            .into()
+    }
+
+    #[test]
+    fn status_decides_whether_to_retry() {
+        for error in [handshake_error, snapshot_error] {
+            for status in [400, 401, 404] {
+                assert!(!error(status).is_retryable(), "{status}");
+            }
+            for status in [403, 418, 429, 451, 500, 503] {
+                assert!(error(status).is_retryable(), "{status}");
+            }
+            assert!(error(418).is_rate_limited());
+            assert!(error(429).is_rate_limited());
+            assert!(!error(503).is_rate_limited());
+        }
+        assert!(WebsocketError::Timeout.is_retryable());
+        assert!(!WebsocketError::SubscriptionRejected("unknown".to_string()).is_retryable());
+    }
+}
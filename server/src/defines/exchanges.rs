@@ -1,14 +1,42 @@
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+use crate::defines::grpc_scheme::ExchangeId;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Exchange {
     Binance,
     Bitstamp,
+    Kraken,
+    Coinbase,
 }
 
 impl Exchange {
+    pub(crate) const ALL: [Exchange; 4] = [
+        Exchange::Binance,
+        Exchange::Bitstamp,
+        Exchange::Kraken,
+        Exchange::Coinbase,
+    ];
     pub(crate) fn name(&self) -> &'static str {
         match self {
             Exchange::Binance => "binance",
             Exchange::Bitstamp => "bitstamp",
+            Exchange::Kraken => "kraken",
+            Exchange::Coinbase => "coinbase",
         }
     }
+    /// Id of the exchange in the delta stream
+    pub(crate) fn id(&self) -> ExchangeId {
+        match self {
+            Exchange::Binance => ExchangeId::Binance,
+            Exchange::Bitstamp => ExchangeId::Bitstamp,
+            Exchange::Kraken => ExchangeId::Kraken,
+            Exchange::Coinbase => ExchangeId::Coinbase,
+        }
+    }
+    pub(crate) fn from_name(name: &str) -> Option<Exchange> {
+        Exchange::ALL
+            .into_iter()
+            .find(|exchange| exchange.name().eq_ignore_ascii_case(name))
+    }
 }
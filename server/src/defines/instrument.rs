@@ -0,0 +1,328 @@
+use crate::defines::error::InstrumentError;
+use crate::defines::json_parser::JSONParser;
+use crate::defines::Exchange;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Properties of an instrument on one exchange
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct Listing {
+    /// Overrides the symbol derived by the exchange adapter, e.g. `XBT/USD` instead of `BTC/USD`
+    #[serde(default)]
+    pub native_symbol: Option<String>,
+    pub tick_size: Decimal,
+    pub lot_size: Decimal,
+}
+
+/// Exchange independent description of a traded pair, e.g. `BTC/USDT`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct Instrument {
+    pub base: String,
+    pub quote: String,
+    listings: HashMap<Exchange, Listing>,
+}
+
+impl Instrument {
+    pub fn new(base: &str, quote: &str) -> Self {
+        Self {
+            base: base.to_uppercase(),
+            quote: quote.to_uppercase(),
+            listings: HashMap::new(),
+        }
+    }
+    pub fn with_listing(
+        mut self,
+        exchange: Exchange,
+        tick_size: Decimal,
+        lot_size: Decimal,
+    ) -> Self {
+        self.listings.insert(
+            exchange,
+            Listing {
+                native_symbol: None,
+                tick_size,
+                lot_size,
+            },
+        );
+        self
+    }
+    /// Canonical name in `BASE/QUOTE` notation
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.base, self.quote)
+    }
+    pub fn listing(&self, exchange: Exchange) -> Option<&Listing> {
+        self.listings.get(&exchange)
+    }
+    /// Exchanges the instrument is traded on, in the order of `Exchange::ALL`
+    pub fn exchanges(&self) -> impl Iterator<Item = Exchange> + '_ {
+        Exchange::ALL
+            .into_iter()
+            .filter(|exchange| self.listings.contains_key(exchange))
+    }
+    /// True if `name` is either the canonical name or the concatenated `btcusdt` form
+    fn is_named(&self, name: &str) -> bool {
+        let upper = name.to_uppercase();
+        upper == self.name() || upper == format!("{}{}", self.base, self.quote)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct InstrumentRegistry {
+    instruments: Vec<Instrument>,
+}
+
+impl InstrumentRegistry {
+    /// Registry with the most liquid pairs of all supported exchanges
+    pub fn with_defaults() -> Self {
+        use Exchange::*;
+        let instruments = vec![
+            Instrument::new("BTC", "USDT")
+                .with_listing(Binance, dec!(0.01), dec!(0.00001))
+                .with_listing(Bitstamp, dec!(1), dec!(0.00000001))
+                .with_listing(Kraken, dec!(0.1), dec!(0.00000001))
+                .with_listing(Coinbase, dec!(0.01), dec!(0.00000001)),
+            Instrument::new("BTC", "USD")
+                .with_listing(Bitstamp, dec!(1), dec!(0.00000001))
+                .with_listing(Kraken, dec!(0.1), dec!(0.00000001))
+                .with_listing(Coinbase, dec!(0.01), dec!(0.00000001)),
+            Instrument::new("ETH", "USDT")
+                .with_listing(Binance, dec!(0.01), dec!(0.0001))
+                .with_listing(Bitstamp, dec!(0.1), dec!(0.00000001))
+                .with_listing(Kraken, dec!(0.01), dec!(0.00000001))
+                .with_listing(Coinbase, dec!(0.01), dec!(0.00000001)),
+            Instrument::new("ETH", "USD")
+                .with_listing(Bitstamp, dec!(0.1), dec!(0.00000001))
+                .with_listing(Kraken, dec!(0.01), dec!(0.00000001))
+                .with_listing(Coinbase, dec!(0.01), dec!(0.00000001)),
+            Instrument::new("BTC", "USDC")
+                .with_listing(Binance, dec!(0.01), dec!(0.00001))
+                .with_listing(Bitstamp, dec!(1), dec!(0.00000001))
+                .with_listing(Kraken, dec!(0.01), dec!(0.00000001)),
+            Instrument::new("USDT", "USD")
+                .with_listing(Bitstamp, dec!(0.00001), dec!(0.00001))
+                .with_listing(Kraken, dec!(0.0001), dec!(0.00000001))
+                .with_listing(Coinbase, dec!(0.00001), dec!(0.01)),
+            Instrument::new("USDC", "USD")
+                .with_listing(Bitstamp, dec!(0.00001), dec!(0.00001))
+                .with_listing(Kraken, dec!(0.0001), dec!(0.00000001)),
+            Instrument::new("USDC", "USDT")
+                .with_listing(Binance, dec!(0.0001), dec!(1))
+                .with_listing(Kraken, dec!(0.0001), dec!(0.00000001)),
+        ];
+        Self { instruments }
+    }
+    /// Adds the instruments of a JSON file, replacing default instruments with the same name
+    pub fn extend_from_json(&mut self, json: &str) -> Result<(), InstrumentError> {
+        let instruments: Vec<Instrument> =
+            JSONParser::from_str(json).map_err(InstrumentError::InvalidRegistry)?;
+        for mut instrument in instruments {
+            instrument.base = instrument.base.to_uppercase();
+            instrument.quote = instrument.quote.to_uppercase();
+            self.instruments
+                .retain(|known| known.name() != instrument.name());
+            self.instruments.push(instrument);
+        }
+        Ok(())
+    }
+    /// Looks up `name` given as `BTC/USDT` or `btcusdt`
+    pub fn resolve(&self, name: &str) -> Result<&Instrument, InstrumentError> {
+        self.instruments
+            .iter()
+            .find(|instrument| instrument.is_named(name))
+            .ok_or_else(|| InstrumentError::UnknownInstrument(name.to_string()))
+    }
+    /// Finds an instrument pricing `from` in `to`, either directly or as the inverse pair
+    pub fn conversion(&self, from: &str, to: &str) -> Option<Conversion> {
+        let (instrument, inverted) = self.instruments.iter().find_map(|instrument| {
+            if instrument.base == from && instrument.quote == to {
+                Some((instrument, false))
+            } else if instrument.base == to && instrument.quote == from {
+                Some((instrument, true))
+            } else {
+                None
+            }
+        })?;
+        Some(Conversion {
+            quote: from.to_string(),
+            exchange: instrument.exchanges().next()?,
+            instrument: instrument.clone(),
+            inverted,
+        })
+    }
+}
+
+/// Feed which provides the rate converting books quoted in `quote` into the market's quote
+#[derive(Debug, Clone)]
+pub(crate) struct Conversion {
+    pub quote: String,
+    pub exchange: Exchange,
+    pub instrument: Instrument,
+    /// True if the instrument is quoted in `quote`, e.g. USD/USDT for converting USDT into USD
+    pub inverted: bool,
+}
+
+/// Set of exchange books which are aggregated into one summary
+#[derive(Debug, Clone)]
+pub(crate) struct Market {
+    pub name: String,
+    pub quote: String,
+    pub sources: Vec<(Exchange, Instrument)>,
+    /// One conversion for every quote asset of `sources` other than `quote`
+    pub conversions: Vec<Conversion>,
+}
+
+impl Market {
+    /// Parses `BTC/USD` which aggregates the instrument on all exchanges listing it.
+    /// Single exchanges can be given another instrument, e.g. `BTC/USD;binance=BTC/USDT`
+    pub fn parse(spec: &str, registry: &InstrumentRegistry) -> Result<Self, InstrumentError> {
+        let mut parts = spec.split(';');
+        let instrument = registry.resolve(parts.next().unwrap_or_default().trim())?;
+        let mut sources: Vec<(Exchange, Instrument)> = instrument
+            .exchanges()
+            .map(|exchange| (exchange, instrument.clone()))
+            .collect();
+        for part in parts {
+            let (exchange_name, instrument_name) = part
+                .split_once('=')
+                .ok_or_else(|| InstrumentError::InvalidMarket(spec.to_string()))?;
+            let exchange = Exchange::from_name(exchange_name.trim())
+                .ok_or_else(|| InstrumentError::InvalidMarket(spec.to_string()))?;
+            let source_instrument = registry.resolve(instrument_name.trim())?;
+            if source_instrument.listing(exchange).is_none() {
+                return Err(InstrumentError::NotListed {
+                    instrument: source_instrument.name(),
+                    exchange,
+                });
+            }
+            sources.retain(|(known, _)| *known != exchange);
+            sources.push((exchange, source_instrument.clone()));
+        }
+        Self::new(
+            instrument.name(),
+            instrument.quote.clone(),
+            sources,
+            registry,
+        )
+    }
+    /// Books quoted in another asset than `quote` are only aggregated if the registry
+    /// knows an instrument to convert them
+    pub fn new(
+        name: String,
+        quote: String,
+        sources: Vec<(Exchange, Instrument)>,
+        registry: &InstrumentRegistry,
+    ) -> Result<Self, InstrumentError> {
+        let mut conversions: Vec<Conversion> = Vec::new();
+        for (exchange, instrument) in &sources {
+            if instrument.quote == quote
+                || conversions
+                    .iter()
+                    .any(|conversion| conversion.quote == instrument.quote)
+            {
+                continue;
+            }
+            let conversion = registry
+                .conversion(&instrument.quote, &quote)
+                .ok_or_else(|| InstrumentError::QuoteMismatch {
+                    market: name.clone(),
+                    exchange: *exchange,
+                    quote: instrument.quote.clone(),
+                })?;
+            conversions.push(conversion);
+        }
+        Ok(Self {
+            name,
+            quote,
+            sources,
+            conversions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::error::InstrumentError;
+    use crate::defines::instrument::{InstrumentRegistry, Market};
+    use crate::defines::Exchange;
+
+    #[test]
+    fn resolves_canonical_and_concatenated_names() {
+        let registry = InstrumentRegistry::with_defaults();
+        assert_eq!(registry.resolve("btcusdt").unwrap().name(), "BTC/USDT");
+        assert_eq!(registry.resolve("btc/usd").unwrap().name(), "BTC/USD");
+        assert!(registry.resolve("foobar").is_err());
+    }
+
+    #[test]
+    fn market_uses_all_listing_exchanges() {
+        let registry = InstrumentRegistry::with_defaults();
+        let market = Market::parse("BTC/USD", &registry).unwrap();
+        let exchanges: Vec<Exchange> = market.sources.iter().map(|(e, _)| *e).collect();
+        assert_eq!(
+            exchanges,
+            vec![Exchange::Bitstamp, Exchange::Kraken, Exchange::Coinbase]
+        );
+    }
+
+    #[test]
+    fn foreign_quotes_get_a_conversion() {
+        let registry = InstrumentRegistry::with_defaults();
+        let market = Market::parse("BTC/USD;binance=BTC/USDT;kraken=BTC/USDC", &registry).unwrap();
+        let conversions: Vec<(&str, Exchange, String, bool)> = market
+            .conversions
+            .iter()
+            .map(|c| {
+                (
+                    c.quote.as_str(),
+                    c.exchange,
+                    c.instrument.name(),
+                    c.inverted,
+                )
+            })
+            .collect();
+        assert_eq!(
+            conversions,
+            vec![
+                ("USDT", Exchange::Bitstamp, "USDT/USD".to_string(), false),
+                ("USDC", Exchange::Bitstamp, "USDC/USD".to_string(), false),
+            ]
+        );
+        let market = Market::parse("BTC/USDT;coinbase=BTC/USD", &registry).unwrap();
+        assert!(market.conversions[0].inverted);
+    }
+
+    #[test]
+    fn quotes_without_conversion_are_rejected() {
+        let mut registry = InstrumentRegistry::with_defaults();
+        registry
+            .extend_from_json(
+                r#"[{"base":"btc","quote":"eur","listings":{"kraken":
+                {"tick_size":"0.1","lot_size":"0.00000001"}}}]"#,
+            )
+            .unwrap();
+        assert!(matches!(
+            Market::parse("BTC/USD;kraken=BTC/EUR", &registry),
+            Err(InstrumentError::QuoteMismatch {
+                exchange: Exchange::Kraken,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn registry_file_overrides_defaults() {
+        let mut registry = InstrumentRegistry::with_defaults();
+        registry
+            .extend_from_json(
+                r#"[{"base":"btc","quote":"usd","listings":{"kraken":
+                {"native_symbol":"XBT/USD","tick_size":"0.1","lot_size":"0.00000001"}}}]"#,
+            )
+            .unwrap();
+        let instrument = registry.resolve("BTC/USD").unwrap();
+        assert_eq!(instrument.exchanges().count(), 1);
+        let listing = instrument.listing(Exchange::Kraken).unwrap();
+        assert_eq!(listing.native_symbol.as_deref(), Some("XBT/USD"));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::defines::error::{WebsocketError, WebsocketResult};
+use crate::defines::instrument::Instrument;
+use crate::defines::json_parser::JSONParser;
+use crate::defines::Exchange;
+use crate::feed::exchanges::binance::json_messages::{
+    BookSubRequest, DepthSnapshot, DiffDepthMessage,
+};
+use crate::feed::exchanges::binance::BinanceOrderbookWsApi;
+use crate::feed::local_book::LocalBook;
+use crate::feed::ws_api_feed::{KeepAlive, OrderbookWsApi};
+use crate::marketdata::Orderbook;
+use std::time::Duration;
+use url::Url;
+
+// Number of levels requested from the REST snapshot endpoint
+const SNAPSHOT_LIMIT: usize = 1000;
+
+#[derive(Debug, Copy, Clone)]
+pub(in crate::feed) enum DiffUpdateSpeed {
+    Ms100,
+    Ms1000,
+}
+
+/// Full depth book from the `@depth` diff stream, synchronised with a REST snapshot as
+/// described in Binance's "How to manage a local order book correctly"
+#[derive(Clone)]
+pub(in crate::feed) struct BinanceDiffOrderbookWsApi {
+    snapshot_endpoint: Url,
+    update_speed: DiffUpdateSpeed,
+    book: LocalBook,
+    // Final update id of the last applied event, `None` until the snapshot is loaded
+    last_update_id: Option<u64>,
+    // True once the first event following the snapshot has been applied
+    synchronised: bool,
+    // Levels per side passed on
+    depth: usize,
+}
+
+impl BinanceDiffOrderbookWsApi {
+    pub fn new(snapshot_endpoint: Url, update_speed: DiffUpdateSpeed, depth: usize) -> Self {
+        Self {
+            snapshot_endpoint,
+            update_speed,
+            book: LocalBook::default(),
+            last_update_id: None,
+            synchronised: false,
+            depth,
+        }
+    }
+
+    fn stream(&self, symbol: &str) -> String {
+        match self.update_speed {
+            DiffUpdateSpeed::Ms100 => format!("{symbol}@depth@100ms"),
+            DiffUpdateSpeed::Ms1000 => format!("{symbol}@depth"),
+        }
+    }
+}
+
+impl OrderbookWsApi for BinanceDiffOrderbookWsApi {
+    fn native_symbol(instrument: &Instrument) -> String {
+        BinanceOrderbookWsApi::native_symbol(instrument)
+    }
+
+    fn subscription_message(&self, symbol: &str) -> String {
+        JSONParser::to_string(&BookSubRequest::new(self.stream(symbol))).unwrap()
+    }
+
+    fn unsubscription_message(&self, symbol: &str) -> Option<String> {
+        JSONParser::to_string(&BookSubRequest::unsubscribe(self.stream(symbol))).ok()
+    }
+
+    fn verify_confirmation(symbol: &str, message: &str) -> bool {
+        BinanceOrderbookWsApi::verify_confirmation(symbol, message)
+    }
+
+    fn subscription_error(message: &str) -> Option<String> {
+        BinanceOrderbookWsApi::subscription_error(message)
+    }
+
+    fn handle_message(&mut self, msg: &str) -> WebsocketResult<Option<Orderbook>> {
+        let event = JSONParser::from_str::<DiffDepthMessage>(msg)?.data;
+        let Some(last_update_id) = self.last_update_id else {
+            return Err(WebsocketError::BookOutOfSync("update before snapshot"));
+        };
+        // Event is already contained in the snapshot
+        if event.final_update_id <= last_update_id {
+            return Ok(None);
+        }
+        // The first event has to overlap the snapshot, all later ones have to be contiguous
+        let in_sequence = if self.synchronised {
+            event.first_update_id == last_update_id + 1
+        } else {
+            event.first_update_id <= last_update_id + 1
+        };
+        if !in_sequence {
+            return Err(WebsocketError::BookOutOfSync("missed depth update"));
+        }
+        event
+            .bids
+            .into_iter()
+            .for_each(|level| self.book.update_bid(level));
+        event
+            .asks
+            .into_iter()
+            .for_each(|level| self.book.update_ask(level));
+        self.last_update_id = Some(event.final_update_id);
+        self.synchronised = true;
+        let book = self.book.to_orderbook(self.depth);
+        Ok(Some(book.with_exchange_time(event.event_time * 1000)))
+    }
+
+    fn snapshot_endpoint(&self, symbol: &str) -> Option<Url> {
+        let mut endpoint = self.snapshot_endpoint.clone();
+        endpoint
+            .query_pairs_mut()
+            .append_pair("symbol", &symbol.to_uppercase())
+            .append_pair("limit", &SNAPSHOT_LIMIT.to_string());
+        Some(endpoint)
+    }
+
+    fn handle_snapshot(&mut self, msg: &str) -> WebsocketResult<()> {
+        let snapshot: DepthSnapshot = JSONParser::from_str(msg)?;
+        self.book.clear();
+        snapshot
+            .bids
+            .into_iter()
+            .for_each(|level| self.book.update_bid(level));
+        snapshot
+            .asks
+            .into_iter()
+            .for_each(|level| self.book.update_ask(level));
+        self.last_update_id = Some(snapshot.last_update_id);
+        self.synchronised = false;
+        Ok(())
+    }
+
+    fn connection_endpoint() -> Url {
+        BinanceOrderbookWsApi::connection_endpoint()
+    }
+
+    fn exchange() -> Exchange {
+        Exchange::Binance
+    }
+
+    fn keep_alive() -> KeepAlive {
+        KeepAlive {
+            inactivity_timeout: Some(Duration::from_secs(30)),
+            ..KeepAlive::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::error::WebsocketError;
+    use crate::feed::exchanges::binance::{BinanceDiffOrderbookWsApi, DiffUpdateSpeed};
+    use crate::feed::mock_servers::{serve_frames, serve_http};
+    use crate::feed::ws_api_feed::OrderbookWebsocket;
+    use rust_decimal_macros::dec;
+
+    const SNAPSHOT: &str = r#"{"lastUpdateId":100,"bids":[["100.00","1.0"],["99.00","2.0"]],"asks":[["101.00","1.0"],["102.00","3.0"]]}"#;
+
+    fn diff(first: u64, last: u64, bids: &str, asks: &str) -> String {
+        format!(
+            r#"{{"stream":"btcusdt@depth@100ms","data":{{"e":"depthUpdate","E":1687000000000,"s":"BTCUSDT","U":{first},"u":{last},"b":{bids},"a":{asks}}}}}"#
+        )
+    }
+
+    async fn connect(frames: Vec<String>) -> OrderbookWebsocket<BinanceDiffOrderbookWsApi> {
+        let mut all_frames = vec![r#"{"result":null,"id":1}"#.to_string()];
+        all_frames.extend(frames);
+        let ws_endpoint = serve_frames(all_frames).await;
+        let snapshot_endpoint = serve_http(SNAPSHOT.to_string()).await;
+        let api = BinanceDiffOrderbookWsApi::new(snapshot_endpoint, DiffUpdateSpeed::Ms100, 10);
+        OrderbookWebsocket::connect_and_subscribe_to(api, ws_endpoint, "btcusdt")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn applies_diffs_after_snapshot() {
+        let mut ws = connect(vec![
+            // already contained in the snapshot
+            diff(95, 100, r#"[["100.00","5.0"]]"#, "[]"),
+            diff(99, 103, r#"[["100.00","0"]]"#, "[]"),
+            diff(104, 105, "[]", r#"[["100.50","1.5"]]"#),
+        ])
+        .await;
+        ws.next_book().await.unwrap();
+        assert_eq!(ws.api().book.bids().next().unwrap().price, dec!(99));
+        ws.next_book().await.unwrap();
+        assert_eq!(ws.api().book.asks().next().unwrap().price, dec!(100.5));
+        assert_eq!(ws.api().last_update_id, Some(105));
+    }
+
+    #[tokio::test]
+    async fn gap_forces_reconnect() {
+        let mut ws = connect(vec![
+            diff(99, 103, r#"[["100.00","0"]]"#, "[]"),
+            diff(105, 106, "[]", r#"[["100.50","1.5"]]"#),
+        ])
+        .await;
+        ws.next_book().await.unwrap();
+        assert!(matches!(
+            ws.next_book().await,
+            Err(WebsocketError::BookOutOfSync(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn first_event_has_to_overlap_snapshot() {
+        let mut ws = connect(vec![diff(102, 103, r#"[["100.00","0"]]"#, "[]")]).await;
+        assert!(matches!(
+            ws.next_book().await,
+            Err(WebsocketError::BookOutOfSync(_))
+        ));
+    }
+}
@@ -1,7 +1,5 @@
-use crate::feed::exchanges::binance::BINANCE_BOOK_DEPTH;
-use crate::marketdata::BookLevel;
+use crate::marketdata::{BookLevel, BookLevels};
 use serde::{Deserialize, Serialize};
-use smallvec::SmallVec;
 
 #[derive(Serialize)]
 pub struct BookSubRequest {
@@ -11,20 +9,32 @@ pub struct BookSubRequest {
 }
 
 impl BookSubRequest {
-    const FIRST_ID: usize = 1;
-    pub fn new(symbol: &str) -> Self {
+    pub const FIRST_ID: usize = 1;
+    pub fn new(stream: String) -> Self {
         Self {
             method: String::from("SUBSCRIBE"),
-            params: vec![format!("{symbol}@depth{BINANCE_BOOK_DEPTH}@100ms")],
+            params: vec![stream],
             id: Self::FIRST_ID,
         }
     }
+    pub fn unsubscribe(stream: String) -> Self {
+        Self {
+            method: String::from("UNSUBSCRIBE"),
+            params: vec![stream],
+            id: Self::FIRST_ID + 1,
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct BookSubRequestResponse {
     pub result: Option<()>,
     pub id: usize,
+    pub error: Option<ErrorMessage>,
+}
+#[derive(Deserialize)]
+pub struct ErrorMessage {
+    pub msg: String,
 }
 #[derive(Deserialize)]
 pub(crate) struct BinanceBookMessage {
@@ -33,6 +43,34 @@ pub(crate) struct BinanceBookMessage {
 
 #[derive(Deserialize)]
 pub(crate) struct BinanceBookMessageData {
-    pub bids: SmallVec<[BookLevel; BINANCE_BOOK_DEPTH]>,
-    pub asks: SmallVec<[BookLevel; BINANCE_BOOK_DEPTH]>,
+    pub bids: BookLevels,
+    pub asks: BookLevels,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DepthSnapshot {
+    #[serde(rename = "lastUpdateId")]
+    pub last_update_id: u64,
+    pub bids: Vec<BookLevel>,
+    pub asks: Vec<BookLevel>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DiffDepthMessage {
+    pub data: DiffDepthMessageData,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DiffDepthMessageData {
+    /// Event time in milliseconds since the unix epoch
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "U")]
+    pub first_update_id: u64,
+    #[serde(rename = "u")]
+    pub final_update_id: u64,
+    #[serde(rename = "b")]
+    pub bids: Vec<BookLevel>,
+    #[serde(rename = "a")]
+    pub asks: Vec<BookLevel>,
 }
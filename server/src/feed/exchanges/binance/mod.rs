@@ -1,27 +1,65 @@
+mod diff_depth;
 mod json_messages;
 
-use crate::defines::json_parser::{JSONError, JSONParser};
+use crate::defines::error::WebsocketResult;
+use crate::defines::instrument::Instrument;
+use crate::defines::json_parser::JSONParser;
 use crate::defines::Exchange;
 use crate::feed::exchanges::binance::json_messages::{
     BinanceBookMessage, BookSubRequest, BookSubRequestResponse,
 };
-use crate::feed::ws_api_feed::OrderbookWsApi;
+use crate::feed::ws_api_feed::{KeepAlive, OrderbookWsApi};
 use crate::marketdata::Orderbook;
 use log::error;
+use std::time::Duration;
 use url::Url;
 
-const BINANCE_BOOK_DEPTH: usize = 10;
+pub(in crate::feed) use diff_depth::{BinanceDiffOrderbookWsApi, DiffUpdateSpeed};
 
-pub(in crate::feed) struct BinanceOrderbookWsApi {}
+// Depths offered by the partial book streams
+const PARTIAL_BOOK_DEPTHS: [usize; 3] = [5, 10, 20];
+
+#[derive(Clone)]
+pub(in crate::feed) struct BinanceOrderbookWsApi {
+    // Levels per side passed on
+    depth: usize,
+}
+
+impl BinanceOrderbookWsApi {
+    pub fn new(depth: usize) -> Self {
+        Self { depth }
+    }
+
+    /// Levels of the subscribed partial book stream, at most 20 are available
+    pub fn max_depth() -> usize {
+        PARTIAL_BOOK_DEPTHS[PARTIAL_BOOK_DEPTHS.len() - 1]
+    }
+
+    fn stream(&self, symbol: &str) -> String {
+        let depth = PARTIAL_BOOK_DEPTHS
+            .into_iter()
+            .find(|depth| *depth >= self.depth)
+            .unwrap_or(Self::max_depth());
+        format!("{symbol}@depth{depth}@100ms")
+    }
+}
 
 impl OrderbookWsApi for BinanceOrderbookWsApi {
-    fn subscription_message(symbol: &str) -> String {
-        JSONParser::to_string(&BookSubRequest::new(symbol)).unwrap()
+    fn native_symbol(instrument: &Instrument) -> String {
+        format!("{}{}", instrument.base, instrument.quote).to_lowercase()
+    }
+
+    fn subscription_message(&self, symbol: &str) -> String {
+        JSONParser::to_string(&BookSubRequest::new(self.stream(symbol))).unwrap()
+    }
+
+    fn unsubscription_message(&self, symbol: &str) -> Option<String> {
+        JSONParser::to_string(&BookSubRequest::unsubscribe(self.stream(symbol))).ok()
     }
 
     fn verify_confirmation(_symbol: &str, message: &str) -> bool {
         match JSONParser::from_str::<BookSubRequestResponse>(message) {
-            Ok(x) => x.result.is_none(),
+            Ok(x) => x.result.is_none() && x.error.is_none() && x.id == BookSubRequest::FIRST_ID,
             Err(e) => {
                 error!(target : "BinanceFeed", "Unexpected json {message}; {e:?}");
                 false
@@ -29,10 +67,18 @@ impl OrderbookWsApi for BinanceOrderbookWsApi {
         }
     }
 
-    fn handle_message(msg: &str) -> Result<Orderbook, JSONError> {
+    fn subscription_error(message: &str) -> Option<String> {
+        JSONParser::from_str::<BookSubRequestResponse>(message)
+            .ok()?
+            .error
+            .map(|error| error.msg)
+    }
+
+    fn handle_message(&mut self, msg: &str) -> WebsocketResult<Option<Orderbook>> {
         let binance_msg: BinanceBookMessage = JSONParser::from_str(msg)?;
-        let book = Orderbook::new(binance_msg.data.bids, binance_msg.data.asks);
-        Ok(book)
+        let mut book = Orderbook::new(binance_msg.data.bids, binance_msg.data.asks);
+        book.truncate(self.depth);
+        Ok(Some(book))
     }
 
     fn connection_endpoint() -> Url {
@@ -42,4 +88,47 @@ impl OrderbookWsApi for BinanceOrderbookWsApi {
     fn exchange() -> Exchange {
         Exchange::Binance
     }
+
+    // Partial books are pushed every 100ms even if nothing changed
+    fn keep_alive() -> KeepAlive {
+        KeepAlive {
+            inactivity_timeout: Some(Duration::from_secs(10)),
+            ..KeepAlive::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::feed::exchanges::binance::BinanceOrderbookWsApi;
+    use crate::feed::ws_api_feed::OrderbookWsApi;
+    use rust_decimal_macros::dec;
+
+    const MESSAGE: &str = r#"{"stream":"btcusdt@depth5@100ms","data":{"lastUpdateId":1,
+        "bids":[["100.0","1.0"],["99.0","1.0"],["98.0","1.0"],["97.0","1.0"],["96.0","1.0"]],
+        "asks":[["101.0","1.0"],["102.0","1.0"],["103.0","1.0"],["104.0","1.0"],["105.0","1.0"]]}}"#;
+
+    #[test]
+    fn smallest_stream_covering_depth() {
+        let api = BinanceOrderbookWsApi::new(3);
+        assert!(api
+            .subscription_message("btcusdt")
+            .contains("btcusdt@depth5@100ms"));
+        let api = BinanceOrderbookWsApi::new(15);
+        assert!(api
+            .subscription_message("btcusdt")
+            .contains("btcusdt@depth20@100ms"));
+        let api = BinanceOrderbookWsApi::new(50);
+        assert!(api
+            .subscription_message("btcusdt")
+            .contains("btcusdt@depth20@100ms"));
+    }
+
+    #[test]
+    fn books_are_truncated_to_depth() {
+        let mut api = BinanceOrderbookWsApi::new(3);
+        let book = api.handle_message(MESSAGE).unwrap().unwrap();
+        assert_eq!((book.bids().len(), book.asks().len()), (3, 3));
+        assert_eq!(book.mid_price(), Some(dec!(100.5)));
+    }
 }
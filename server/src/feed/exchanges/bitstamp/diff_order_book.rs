@@ -0,0 +1,194 @@
+use crate::defines::error::{WebsocketError, WebsocketResult};
+use crate::defines::instrument::Instrument;
+use crate::defines::json_parser::JSONParser;
+use crate::defines::Exchange;
+use crate::feed::exchanges::bitstamp::json_messages::{
+    BookSubRequest, DiffBookMessage, DiffBookMessageData,
+};
+use crate::feed::exchanges::bitstamp::{
+    parse_channel_message, verify_channel_confirmation, BitstampOrderbookWsApi,
+};
+use crate::feed::local_book::LocalBook;
+use crate::feed::ws_api_feed::{KeepAlive, OrderbookWsApi};
+use crate::marketdata::Orderbook;
+use url::Url;
+
+/// Full depth book from the `diff_order_book` channel, initialised from the REST order book.
+/// Updates are ordered by their `microtimestamp`, which is not unique
+#[derive(Clone)]
+pub(in crate::feed) struct BitstampDiffOrderbookWsApi {
+    snapshot_endpoint: Url,
+    book: LocalBook,
+    // Microtimestamp of the snapshot or of the last applied update, `None` until the
+    // snapshot is loaded
+    last_microtimestamp: Option<u64>,
+    // True once the first update following the snapshot has been applied
+    synchronised: bool,
+    // Levels per side passed on
+    depth: usize,
+}
+
+impl BitstampDiffOrderbookWsApi {
+    pub fn new(snapshot_endpoint: Url, depth: usize) -> Self {
+        Self {
+            snapshot_endpoint,
+            book: LocalBook::default(),
+            last_microtimestamp: None,
+            synchronised: false,
+            depth,
+        }
+    }
+
+    fn apply(&mut self, data: DiffBookMessageData) {
+        data.bids
+            .into_iter()
+            .for_each(|level| self.book.update_bid(level));
+        data.asks
+            .into_iter()
+            .for_each(|level| self.book.update_ask(level));
+        self.last_microtimestamp = Some(data.microtimestamp);
+    }
+}
+
+impl OrderbookWsApi for BitstampDiffOrderbookWsApi {
+    fn native_symbol(instrument: &Instrument) -> String {
+        BitstampOrderbookWsApi::native_symbol(instrument)
+    }
+
+    fn subscription_message(&self, symbol: &str) -> String {
+        let channel = BookSubRequest::diff_order_book_channel(symbol);
+        JSONParser::to_string(&BookSubRequest::new(channel)).unwrap()
+    }
+
+    fn unsubscription_message(&self, symbol: &str) -> Option<String> {
+        let channel = BookSubRequest::diff_order_book_channel(symbol);
+        JSONParser::to_string(&BookSubRequest::unsubscribe(channel)).ok()
+    }
+
+    fn verify_confirmation(symbol: &str, message: &str) -> bool {
+        verify_channel_confirmation(&BookSubRequest::diff_order_book_channel(symbol), message)
+    }
+
+    fn subscription_error(message: &str) -> Option<String> {
+        BitstampOrderbookWsApi::subscription_error(message)
+    }
+
+    fn handle_message(&mut self, msg: &str) -> WebsocketResult<Option<Orderbook>> {
+        let Some(DiffBookMessage { data: update }) = parse_channel_message(msg)? else {
+            return Ok(None);
+        };
+        let Some(last_microtimestamp) = self.last_microtimestamp else {
+            return Err(WebsocketError::BookOutOfSync("update before snapshot"));
+        };
+        // Updates up to the time of the snapshot are already part of it
+        if !self.synchronised && update.microtimestamp <= last_microtimestamp {
+            return Ok(None);
+        }
+        // Several updates may be published within the same microsecond
+        if update.microtimestamp < last_microtimestamp {
+            return Err(WebsocketError::BookOutOfSync("update out of order"));
+        }
+        let microtimestamp = update.microtimestamp;
+        self.apply(update);
+        self.synchronised = true;
+        if self.book.is_crossed() {
+            return Err(WebsocketError::BookOutOfSync("crossed book"));
+        }
+        let book = self.book.to_orderbook(self.depth);
+        Ok(Some(book.with_exchange_time(microtimestamp)))
+    }
+
+    fn snapshot_endpoint(&self, symbol: &str) -> Option<Url> {
+        self.snapshot_endpoint.join(&format!("{symbol}/")).ok()
+    }
+
+    fn handle_snapshot(&mut self, msg: &str) -> WebsocketResult<()> {
+        let snapshot: DiffBookMessageData = JSONParser::from_str(msg)?;
+        self.book.clear();
+        self.apply(snapshot);
+        self.synchronised = false;
+        Ok(())
+    }
+
+    fn connection_endpoint() -> Url {
+        BitstampOrderbookWsApi::connection_endpoint()
+    }
+
+    fn exchange() -> Exchange {
+        Exchange::Bitstamp
+    }
+
+    fn keep_alive() -> KeepAlive {
+        BitstampOrderbookWsApi::keep_alive()
+    }
+
+    fn heartbeat_message() -> Option<String> {
+        BitstampOrderbookWsApi::heartbeat_message()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::error::WebsocketError;
+    use crate::feed::exchanges::bitstamp::BitstampDiffOrderbookWsApi;
+    use crate::feed::mock_servers::{serve_frames, serve_http};
+    use crate::feed::ws_api_feed::OrderbookWebsocket;
+    use rust_decimal_macros::dec;
+
+    const SNAPSHOT: &str = r#"{"timestamp":"1687000000","microtimestamp":"1687000000500000","bids":[["27000","1.0"],["26990","2.0"]],"asks":[["27010","0.5"],["27020","1.5"]]}"#;
+
+    fn diff(microtimestamp: u64, bids: &str, asks: &str) -> String {
+        format!(
+            r#"{{"data":{{"timestamp":"1687000000","microtimestamp":"{microtimestamp}","bids":{bids},"asks":{asks}}},"channel":"diff_order_book_btcusd","event":"data"}}"#
+        )
+    }
+
+    async fn connect(frames: Vec<String>) -> OrderbookWebsocket<BitstampDiffOrderbookWsApi> {
+        let mut all_frames = vec![
+            r#"{"event":"bts:subscription_succeeded","channel":"diff_order_book_btcusd","data":{}}"#
+                .to_string(),
+        ];
+        all_frames.extend(frames);
+        let ws_endpoint = serve_frames(all_frames).await;
+        let snapshot_endpoint = serve_http(SNAPSHOT.to_string()).await;
+        let api =
+            BitstampDiffOrderbookWsApi::new(snapshot_endpoint.join("order_book/").unwrap(), 10);
+        OrderbookWebsocket::connect_and_subscribe_to(api, ws_endpoint, "btcusd")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn applies_updates_newer_than_snapshot() {
+        let mut ws = connect(vec![
+            // already part of the snapshot
+            diff(1687000000400000, r#"[["27005","3.0"]]"#, "[]"),
+            diff(1687000000600000, r#"[["27000","0"]]"#, "[]"),
+            r#"{"event":"bts:heartbeat","channel":"","data":{"status":"success"}}"#.to_string(),
+            diff(1687000000700000, "[]", r#"[["27008","0.1"]]"#),
+            // same microtimestamp as the previous update
+            diff(1687000000700000, "[]", r#"[["27008","0"]]"#),
+        ])
+        .await;
+        ws.next_book().await.unwrap();
+        assert_eq!(ws.api().book.bids().next().unwrap().price, dec!(26990));
+        ws.next_book().await.unwrap();
+        assert_eq!(ws.api().book.asks().next().unwrap().price, dec!(27008));
+        ws.next_book().await.unwrap();
+        assert_eq!(ws.api().book.asks().next().unwrap().price, dec!(27010));
+    }
+
+    #[tokio::test]
+    async fn out_of_order_update_forces_reconnect() {
+        let mut ws = connect(vec![
+            diff(1687000000700000, r#"[["27000","0"]]"#, "[]"),
+            diff(1687000000600000, "[]", r#"[["27008","0.1"]]"#),
+        ])
+        .await;
+        ws.next_book().await.unwrap();
+        assert!(matches!(
+            ws.next_book().await,
+            Err(WebsocketError::BookOutOfSync(_))
+        ));
+    }
+}
@@ -1,6 +1,10 @@
-use crate::marketdata::BookLevel;
-use serde::{Deserialize, Serialize};
+use crate::defines::json_parser::JSONError;
+use crate::marketdata::{BookLevel, BookLevels};
+use serde::de::{Error, IgnoredAny, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
 use smallvec::SmallVec;
+use std::fmt::Formatter;
 
 #[derive(Serialize)]
 pub struct BookSubRequest {
@@ -12,14 +16,24 @@ struct BookSubRequestData {
     pub channel: String,
 }
 impl BookSubRequest {
-    pub fn new(symbol: &str) -> Self {
+    pub fn new(channel: String) -> Self {
         Self {
             event: "bts:subscribe".to_string(),
-            data: BookSubRequestData {
-                channel: format!("order_book_{symbol}"),
-            },
+            data: BookSubRequestData { channel },
         }
     }
+    pub fn unsubscribe(channel: String) -> Self {
+        Self {
+            event: "bts:unsubscribe".to_string(),
+            data: BookSubRequestData { channel },
+        }
+    }
+    pub fn order_book_channel(symbol: &str) -> String {
+        format!("order_book_{symbol}")
+    }
+    pub fn diff_order_book_channel(symbol: &str) -> String {
+        format!("diff_order_book_{symbol}")
+    }
 }
 
 #[derive(Deserialize)]
@@ -27,13 +41,93 @@ pub struct BookSubRequestResponse {
     pub event: String,
     pub channel: String,
 }
+pub const HEARTBEAT_EVENT: &str = "bts:heartbeat";
+
+#[derive(Serialize)]
+pub struct HeartbeatRequest {
+    event: &'static str,
+}
+impl Default for HeartbeatRequest {
+    fn default() -> Self {
+        Self {
+            event: HEARTBEAT_EVENT,
+        }
+    }
+}
 #[derive(Deserialize)]
-pub(crate) struct BookMessage {
-    pub data: BookMessageData,
+pub struct EventMessage<'a> {
+    pub event: &'a str,
+}
+#[derive(Deserialize)]
+pub struct ErrorMessage {
+    pub event: String,
+    pub data: ErrorMessageData,
+}
+#[derive(Deserialize)]
+pub struct ErrorMessageData {
+    pub message: String,
+}
+#[derive(Deserialize)]
+pub(crate) struct BookMessage<'a> {
+    #[serde(borrow)]
+    pub data: BookMessageData<'a>,
 }
 
+/// Levels are kept unparsed until the requested depth is known, see `best_levels`
 #[derive(Deserialize)]
-pub(crate) struct BookMessageData {
-    pub bids: SmallVec<[BookLevel; 128]>,
-    pub asks: SmallVec<[BookLevel; 128]>,
+pub(crate) struct BookMessageData<'a> {
+    #[serde(deserialize_with = "u64_from_str")]
+    pub microtimestamp: u64,
+    #[serde(borrow)]
+    pub bids: &'a RawValue,
+    #[serde(borrow)]
+    pub asks: &'a RawValue,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DiffBookMessage {
+    pub data: DiffBookMessageData,
+}
+
+/// Payload of `diff_order_book` messages as well as of the REST order book snapshot
+#[derive(Deserialize)]
+pub(crate) struct DiffBookMessageData {
+    #[serde(deserialize_with = "u64_from_str")]
+    pub microtimestamp: u64,
+    pub bids: Vec<BookLevel>,
+    pub asks: Vec<BookLevel>,
+}
+
+/// Deserializes only the first `depth` levels, the remaining ones are skipped without
+/// being parsed into decimals
+pub(crate) fn best_levels(levels: &RawValue, depth: usize) -> Result<BookLevels, JSONError> {
+    struct BestLevelsVisitor {
+        depth: usize,
+    }
+    impl<'de> Visitor<'de> for BestLevelsVisitor {
+        type Value = BookLevels;
+
+        fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+            formatter.write_str("a list of book levels")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut levels = SmallVec::new();
+            while levels.len() < self.depth {
+                match seq.next_element()? {
+                    Some(level) => levels.push(level),
+                    None => return Ok(levels),
+                }
+            }
+            while seq.next_element::<IgnoredAny>()?.is_some() {}
+            Ok(levels)
+        }
+    }
+    let mut deserializer = serde_json::Deserializer::from_str(levels.get());
+    deserializer.deserialize_seq(BestLevelsVisitor { depth })
+}
+
+fn u64_from_str<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let text: &str = Deserialize::deserialize(deserializer)?;
+    text.parse().map_err(D::Error::custom)
 }
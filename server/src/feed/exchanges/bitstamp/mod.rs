@@ -1,42 +1,69 @@
+mod diff_order_book;
 mod json_messages;
 
-use crate::defines::json_parser::{JSONError, JSONParser};
-use crate::defines::{Exchange, BOOK_LEVELS_USED};
+use crate::defines::error::WebsocketResult;
+use crate::defines::instrument::Instrument;
+use crate::defines::json_parser::JSONParser;
+use crate::defines::Exchange;
 use crate::feed::exchanges::bitstamp::json_messages::{
-    BookMessage, BookSubRequest, BookSubRequestResponse,
+    best_levels, BookMessage, BookSubRequest, BookSubRequestResponse, ErrorMessage, EventMessage,
+    HeartbeatRequest, HEARTBEAT_EVENT,
 };
-use crate::feed::ws_api_feed::OrderbookWsApi;
+use crate::feed::ws_api_feed::{KeepAlive, OrderbookWsApi};
 use crate::marketdata::Orderbook;
 use log::error;
-use smallvec::ToSmallVec;
-use std::cmp::min;
+use serde::Deserialize;
+use std::time::Duration;
 use url::Url;
 
-pub(in crate::feed) struct BitstampOrderbookWsApi {}
+pub(in crate::feed) use diff_order_book::BitstampDiffOrderbookWsApi;
+
+#[derive(Clone)]
+pub(in crate::feed) struct BitstampOrderbookWsApi {
+    // Levels per side passed on, the channel contains up to 100
+    depth: usize,
+}
+
+impl BitstampOrderbookWsApi {
+    pub fn new(depth: usize) -> Self {
+        Self { depth }
+    }
+}
 
 impl OrderbookWsApi for BitstampOrderbookWsApi {
-    fn subscription_message(symbol: &str) -> String {
-        JSONParser::to_string(&BookSubRequest::new(symbol)).unwrap()
+    fn native_symbol(instrument: &Instrument) -> String {
+        format!("{}{}", instrument.base, instrument.quote).to_lowercase()
     }
 
-    fn verify_confirmation(_symbol: &str, message: &str) -> bool {
-        match JSONParser::from_str::<BookSubRequestResponse>(message) {
-            Ok(x) => &x.event == "bts:subscription_succeeded",
-            Err(e) => {
-                error!(target : "BitstampFeed", "Unexpected json {message}; {e:?}");
-                false
-            }
-        }
+    fn subscription_message(&self, symbol: &str) -> String {
+        let channel = BookSubRequest::order_book_channel(symbol);
+        JSONParser::to_string(&BookSubRequest::new(channel)).unwrap()
+    }
+
+    fn unsubscription_message(&self, symbol: &str) -> Option<String> {
+        let channel = BookSubRequest::order_book_channel(symbol);
+        JSONParser::to_string(&BookSubRequest::unsubscribe(channel)).ok()
+    }
+
+    fn verify_confirmation(symbol: &str, message: &str) -> bool {
+        verify_channel_confirmation(&BookSubRequest::order_book_channel(symbol), message)
+    }
+
+    fn subscription_error(message: &str) -> Option<String> {
+        let error = JSONParser::from_str::<ErrorMessage>(message).ok()?;
+        (error.event == "bts:error").then_some(error.data.message)
     }
 
-    fn handle_message(msg: &str) -> Result<Orderbook, JSONError> {
-        let bitstamp_msg: BookMessage = JSONParser::from_str(msg)?;
-        let bid_size = min(BOOK_LEVELS_USED, bitstamp_msg.data.bids.len());
-        let ask_size = min(BOOK_LEVELS_USED, bitstamp_msg.data.asks.len());
-        let bids = bitstamp_msg.data.bids[..bid_size].to_smallvec();
-        let asks = bitstamp_msg.data.asks[..ask_size].to_smallvec();
-        let book = Orderbook::new(bids, asks);
-        Ok(book)
+    fn handle_message(&mut self, msg: &str) -> WebsocketResult<Option<Orderbook>> {
+        let Some(bitstamp_msg) = parse_channel_message::<BookMessage>(msg)? else {
+            return Ok(None);
+        };
+        let book = Orderbook::new(
+            best_levels(bitstamp_msg.data.bids, self.depth)?,
+            best_levels(bitstamp_msg.data.asks, self.depth)?,
+        )
+        .with_exchange_time(bitstamp_msg.data.microtimestamp);
+        Ok(Some(book))
     }
 
     fn connection_endpoint() -> Url {
@@ -46,4 +73,37 @@ impl OrderbookWsApi for BitstampOrderbookWsApi {
     fn exchange() -> Exchange {
         Exchange::Bitstamp
     }
+
+    fn keep_alive() -> KeepAlive {
+        KeepAlive {
+            inactivity_timeout: Some(Duration::from_secs(30)),
+            ..KeepAlive::default()
+        }
+    }
+
+    fn heartbeat_message() -> Option<String> {
+        Some(JSONParser::to_string(&HeartbeatRequest::default()).unwrap())
+    }
+}
+
+/// Parses a channel message, replies to our heartbeats yield `None`
+fn parse_channel_message<'a, T: Deserialize<'a>>(msg: &'a str) -> WebsocketResult<Option<T>> {
+    match JSONParser::from_str::<T>(msg) {
+        Ok(message) => Ok(Some(message)),
+        // Heartbeat replies are rare, so they are only checked for once parsing failed
+        Err(e) => match JSONParser::from_str::<EventMessage>(msg) {
+            Ok(event) if event.event == HEARTBEAT_EVENT => Ok(None),
+            _ => Err(e.into()),
+        },
+    }
+}
+
+fn verify_channel_confirmation(channel: &str, message: &str) -> bool {
+    match JSONParser::from_str::<BookSubRequestResponse>(message) {
+        Ok(x) => &x.event == "bts:subscription_succeeded" && x.channel == channel,
+        Err(e) => {
+            error!(target : "BitstampFeed", "Unexpected json {message}; {e:?}");
+            false
+        }
+    }
 }
@@ -0,0 +1,80 @@
+use crate::marketdata::BookLevel;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+pub const LEVEL2_CHANNEL: &str = "level2_batch";
+
+#[derive(Serialize)]
+pub struct BookSubRequest {
+    #[serde(rename = "type")]
+    kind: String,
+    product_ids: Vec<String>,
+    channels: Vec<String>,
+}
+
+impl BookSubRequest {
+    pub fn new(product_id: &str) -> Self {
+        Self {
+            kind: "subscribe".to_string(),
+            product_ids: vec![product_id.to_string()],
+            channels: vec![LEVEL2_CHANNEL.to_string()],
+        }
+    }
+    pub fn unsubscribe(product_id: &str) -> Self {
+        Self {
+            kind: "unsubscribe".to_string(),
+            ..Self::new(product_id)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BookSubRequestResponse {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub channels: Vec<SubscribedChannel>,
+}
+#[derive(Deserialize)]
+pub struct ErrorMessage {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub message: String,
+    #[serde(default)]
+    pub reason: String,
+}
+#[derive(Deserialize)]
+pub struct SubscribedChannel {
+    pub name: String,
+    pub product_ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub(crate) enum BookMessage {
+    #[serde(rename = "snapshot")]
+    Snapshot {
+        product_id: String,
+        bids: Vec<BookLevel>,
+        asks: Vec<BookLevel>,
+        time: Option<String>,
+    },
+    #[serde(rename = "l2update")]
+    L2Update {
+        product_id: String,
+        changes: Vec<BookChange>,
+        time: Option<String>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BookChange(pub Side, pub Decimal, pub Decimal);
+
+#[derive(Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Side {
+    Buy,
+    Sell,
+}
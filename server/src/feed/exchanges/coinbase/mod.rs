@@ -0,0 +1,292 @@
+mod json_messages;
+
+use crate::defines::error::{WebsocketError, WebsocketResult};
+use crate::defines::instrument::Instrument;
+use crate::defines::json_parser::JSONParser;
+use crate::defines::Exchange;
+use crate::feed::exchanges::coinbase::json_messages::{
+    BookMessage, BookSubRequest, BookSubRequestResponse, ErrorMessage, Side, LEVEL2_CHANNEL,
+};
+use crate::feed::local_book::LocalBook;
+use crate::feed::ws_api_feed::OrderbookWsApi;
+use crate::helper::parse_utc_time_us;
+use crate::marketdata::{BookLevel, Orderbook};
+use log::error;
+use url::Url;
+
+/// Keeps a full depth book from the `level2_batch` channel. Any inconsistency in the
+/// update stream is reported as `WebsocketError::BookOutOfSync`, which makes the feed
+/// reconnect and start over from a fresh snapshot
+#[derive(Clone)]
+pub(in crate::feed) struct CoinbaseOrderbookWsApi {
+    book: LocalBook,
+    product_id: Option<String>,
+    // Levels per side passed on
+    depth: usize,
+}
+
+impl CoinbaseOrderbookWsApi {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            book: LocalBook::default(),
+            product_id: None,
+            depth,
+        }
+    }
+
+    /// Current book with the event time of the last message, if it was sent
+    fn checked_book(&self, time: Option<&str>) -> WebsocketResult<Option<Orderbook>> {
+        if self.book.is_crossed() {
+            return Err(WebsocketError::BookOutOfSync("crossed book"));
+        }
+        let book = self.book.to_orderbook(self.depth);
+        Ok(Some(match time.and_then(parse_utc_time_us) {
+            Some(time_us) => book.with_exchange_time(time_us),
+            None => book,
+        }))
+    }
+}
+
+impl OrderbookWsApi for CoinbaseOrderbookWsApi {
+    fn native_symbol(instrument: &Instrument) -> String {
+        format!("{}-{}", instrument.base, instrument.quote)
+    }
+
+    fn subscription_message(&self, symbol: &str) -> String {
+        JSONParser::to_string(&BookSubRequest::new(symbol)).unwrap()
+    }
+
+    fn unsubscription_message(&self, symbol: &str) -> Option<String> {
+        JSONParser::to_string(&BookSubRequest::unsubscribe(symbol)).ok()
+    }
+
+    fn verify_confirmation(symbol: &str, message: &str) -> bool {
+        match JSONParser::from_str::<BookSubRequestResponse>(message) {
+            Ok(x) => {
+                &x.kind == "subscriptions"
+                    && x.channels.iter().any(|channel| {
+                        channel.name == LEVEL2_CHANNEL
+                            && channel.product_ids.iter().any(|id| id == symbol)
+                    })
+            }
+            Err(e) => {
+                error!(target : "CoinbaseFeed", "Unexpected json {message}; {e:?}");
+                false
+            }
+        }
+    }
+
+    fn subscription_error(message: &str) -> Option<String> {
+        let error = JSONParser::from_str::<ErrorMessage>(message).ok()?;
+        (error.kind == "error").then(|| format!("{} {}", error.message, error.reason))
+    }
+
+    fn handle_message(&mut self, msg: &str) -> WebsocketResult<Option<Orderbook>> {
+        match JSONParser::from_str(msg)? {
+            BookMessage::Snapshot {
+                product_id,
+                bids,
+                asks,
+                time,
+            } => {
+                self.book.clear();
+                bids.into_iter()
+                    .for_each(|level| self.book.update_bid(level));
+                asks.into_iter()
+                    .for_each(|level| self.book.update_ask(level));
+                self.product_id = Some(product_id);
+                self.checked_book(time.as_deref())
+            }
+            BookMessage::L2Update {
+                product_id,
+                changes,
+                time,
+            } => {
+                match &self.product_id {
+                    None => return Err(WebsocketError::BookOutOfSync("update before snapshot")),
+                    Some(id) if *id != product_id => {
+                        return Err(WebsocketError::BookOutOfSync("update for other product"))
+                    }
+                    _ => {}
+                }
+                for change in changes {
+                    let level = BookLevel {
+                        price: change.1,
+                        quantity: change.2,
+                    };
+                    match change.0 {
+                        Side::Buy => self.book.update_bid(level),
+                        Side::Sell => self.book.update_ask(level),
+                    }
+                }
+                self.checked_book(time.as_deref())
+            }
+            BookMessage::Other => Ok(None),
+        }
+    }
+
+    fn connection_endpoint() -> Url {
+        Url::parse("wss://ws-feed.exchange.coinbase.com").unwrap()
+    }
+
+    fn exchange() -> Exchange {
+        Exchange::Coinbase
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::error::WebsocketError;
+    use crate::feed::exchanges::coinbase::CoinbaseOrderbookWsApi;
+    use crate::feed::mock_servers::{serve_frames, serve_frames_recording};
+    use crate::feed::ws_api_feed::{KeepAlive, OrderbookWebsocket};
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const RECORDED_FRAMES: &str = include_str!("recorded_frames.jsonl");
+
+    fn recorded_frames() -> Vec<String> {
+        RECORDED_FRAMES.lines().map(String::from).collect()
+    }
+
+    async fn connect(frames: Vec<String>) -> OrderbookWebsocket<CoinbaseOrderbookWsApi> {
+        let endpoint = serve_frames(frames).await;
+        let api = CoinbaseOrderbookWsApi::new(10);
+        OrderbookWebsocket::connect_and_subscribe_to(api, endpoint, "BTC-USD")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn replays_snapshot_and_updates() {
+        let mut ws = connect(recorded_frames()).await;
+        ws.next_book().await.unwrap();
+        let book = &ws.api().book;
+        assert_eq!(book.bids().next().unwrap().price, dec!(26999.99));
+        assert_eq!(book.asks().next().unwrap().price, dec!(27000.01));
+
+        let update = ws.next_book().await.unwrap();
+        assert_eq!(update.exchange_time_us(), Some(1_687_255_200_100_000));
+        let book = &ws.api().book;
+        // best bid was removed, a new best ask was inserted
+        assert_eq!(book.bids().next().unwrap().price, dec!(26999.50));
+        assert_eq!(book.asks().next().unwrap().quantity, dec!(0.25));
+
+        ws.next_book().await.unwrap();
+        assert_eq!(ws.api().book.bids().next().unwrap().price, dec!(27000.00));
+    }
+
+    #[tokio::test]
+    async fn crossed_book_forces_reconnect() {
+        let mut frames = recorded_frames();
+        // bid above the best ask can only result from missed updates
+        frames.push(
+            r#"{"type":"l2update","product_id":"BTC-USD","changes":[["buy","27100.00","1.0"]],"time":"2023-06-20T10:00:00.300000Z"}"#
+                .to_string(),
+        );
+        let mut ws = connect(frames).await;
+        for _ in 0..3 {
+            ws.next_book().await.unwrap();
+        }
+        assert!(matches!(
+            ws.next_book().await,
+            Err(WebsocketError::BookOutOfSync(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn update_before_snapshot_forces_reconnect() {
+        let mut frames = recorded_frames();
+        frames.remove(1);
+        let mut ws = connect(frames).await;
+        assert!(matches!(
+            ws.next_book().await,
+            Err(WebsocketError::BookOutOfSync(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejected_subscription_is_fatal() {
+        let endpoint = serve_frames(vec![
+            r#"{"type":"error","message":"Failed to subscribe","reason":"BTC-FOO is not a valid product"}"#
+                .to_string(),
+        ])
+        .await;
+        let result = OrderbookWebsocket::connect_and_subscribe_to(
+            CoinbaseOrderbookWsApi::new(10),
+            endpoint,
+            "BTC-FOO",
+        )
+        .await;
+        let Err(error) = result else {
+            panic!("subscription should be rejected");
+        };
+        assert!(matches!(error, WebsocketError::SubscriptionRejected(_)));
+        assert!(!error.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn silent_stream_forces_reconnect() {
+        let mut ws = connect(recorded_frames()).await;
+        ws.set_keep_alive(KeepAlive {
+            ping_interval: Duration::from_millis(20),
+            inactivity_timeout: Some(Duration::from_millis(200)),
+        });
+        // The mock server keeps answering pings after the recorded frames
+        let error = loop {
+            if let Err(e) = ws.next_book().await {
+                break e;
+            }
+        };
+        assert!(matches!(
+            error,
+            WebsocketError::Inactive("no book update received")
+        ));
+    }
+
+    #[tokio::test]
+    async fn quiet_book_without_inactivity_timeout_stays_connected() {
+        let mut ws = connect(recorded_frames()).await;
+        ws.set_keep_alive(KeepAlive {
+            ping_interval: Duration::from_millis(20),
+            inactivity_timeout: None,
+        });
+        // Pongs of the mock server keep the connection alive without any book update
+        let result = timeout(Duration::from_millis(300), async {
+            loop {
+                if let Err(e) = ws.next_book().await {
+                    break e;
+                }
+            }
+        })
+        .await;
+        assert!(result.is_err(), "connection failed: {result:?}");
+    }
+
+    #[tokio::test]
+    async fn close_unsubscribes() {
+        let (endpoint, mut client_messages) = serve_frames_recording(recorded_frames()).await;
+        let mut ws = OrderbookWebsocket::connect_and_subscribe_to(
+            CoinbaseOrderbookWsApi::new(10),
+            endpoint,
+            "BTC-USD",
+        )
+        .await
+        .unwrap();
+        ws.next_book().await.unwrap();
+        ws.close().await;
+
+        let subscription = client_messages.recv().await.unwrap();
+        assert!(subscription
+            .to_text()
+            .unwrap()
+            .contains(r#""type":"subscribe""#));
+        let unsubscription = client_messages.recv().await.unwrap();
+        assert_eq!(
+            unsubscription.to_text().unwrap(),
+            r#"{"type":"unsubscribe","product_ids":["BTC-USD"],"channels":["level2_batch"]}"#
+        );
+        assert!(client_messages.recv().await.unwrap().is_close());
+    }
+}
@@ -0,0 +1,114 @@
+use crate::marketdata::{BookLevel, INLINE_BOOK_LEVELS};
+use rust_decimal::Decimal;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
+use smallvec::SmallVec;
+
+#[derive(Serialize)]
+pub struct BookSubRequest {
+    method: String,
+    params: BookSubRequestParams,
+}
+#[derive(Serialize)]
+struct BookSubRequestParams {
+    channel: String,
+    symbol: Vec<String>,
+    depth: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot: Option<bool>,
+}
+impl BookSubRequest {
+    pub fn new(symbol: &str, depth: usize) -> Self {
+        Self {
+            method: "subscribe".to_string(),
+            params: BookSubRequestParams {
+                channel: "book".to_string(),
+                symbol: vec![symbol.to_string()],
+                depth,
+                snapshot: Some(true),
+            },
+        }
+    }
+    pub fn unsubscribe(symbol: &str, depth: usize) -> Self {
+        Self {
+            method: "unsubscribe".to_string(),
+            params: BookSubRequestParams {
+                channel: "book".to_string(),
+                symbol: vec![symbol.to_string()],
+                depth,
+                snapshot: None,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PingRequest {
+    method: &'static str,
+}
+impl Default for PingRequest {
+    fn default() -> Self {
+        Self { method: "ping" }
+    }
+}
+
+// Kraken sends status messages before the confirmation, hence all fields are optional
+#[derive(Deserialize)]
+pub struct BookSubRequestResponse {
+    pub method: Option<String>,
+    pub success: Option<bool>,
+    pub result: Option<BookSubRequestResult>,
+    pub error: Option<String>,
+}
+#[derive(Deserialize)]
+pub struct BookSubRequestResult {
+    pub channel: String,
+    pub symbol: String,
+}
+
+/// Envelope of every channel message; `data` is only parsed for book messages
+#[derive(Deserialize)]
+pub(crate) struct ChannelMessage<'a> {
+    pub channel: Option<&'a str>,
+    #[serde(rename = "type")]
+    pub kind: Option<&'a str>,
+    #[serde(borrow)]
+    pub data: Option<&'a RawValue>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BookMessageData {
+    pub bids: SmallVec<[KrakenBookLevel; INLINE_BOOK_LEVELS]>,
+    pub asks: SmallVec<[KrakenBookLevel; INLINE_BOOK_LEVELS]>,
+    pub checksum: u32,
+    /// Time of the book event, only sent with updates
+    pub timestamp: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct KrakenBookLevel {
+    #[serde(deserialize_with = "exact_decimal")]
+    pub price: Decimal,
+    #[serde(deserialize_with = "exact_decimal")]
+    pub qty: Decimal,
+}
+
+impl From<&KrakenBookLevel> for BookLevel {
+    fn from(value: &KrakenBookLevel) -> Self {
+        BookLevel {
+            price: value.price,
+            quantity: value.qty,
+        }
+    }
+}
+
+/// Kraken v2 sends prices as JSON numbers. They are parsed from their textual representation
+/// so that the scale is preserved, which is required to reproduce the checksum
+fn exact_decimal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+    let raw: &RawValue = Deserialize::deserialize(deserializer)?;
+    let text = raw.get();
+    Decimal::from_str_exact(text)
+        .or_else(|_| Decimal::from_scientific(text))
+        .map_err(D::Error::custom)
+}
@@ -0,0 +1,301 @@
+mod json_messages;
+
+use crate::defines::error::{WebsocketError, WebsocketResult};
+use crate::defines::instrument::{Instrument, Listing};
+use crate::defines::json_parser::JSONParser;
+use crate::defines::Exchange;
+use crate::feed::exchanges::kraken::json_messages::{
+    BookMessageData, BookSubRequest, BookSubRequestResponse, ChannelMessage, PingRequest,
+};
+use crate::feed::local_book::LocalBook;
+use crate::feed::ws_api_feed::OrderbookWsApi;
+use crate::helper::parse_utc_time_us;
+use crate::marketdata::{BookLevel, Orderbook};
+use log::error;
+use rust_decimal::Decimal;
+use smallvec::SmallVec;
+use url::Url;
+
+// Depths offered by the book channel
+const KRAKEN_BOOK_DEPTHS: [usize; 5] = [10, 25, 100, 500, 1000];
+// Number of levels per side covered by the checksum
+const KRAKEN_CHECKSUM_DEPTH: usize = 10;
+
+#[derive(Clone)]
+pub(in crate::feed) struct KrakenOrderbookWsApi {
+    book: LocalBook,
+    snapshot_received: bool,
+    // Levels per side passed on
+    depth: usize,
+    // Levels per side of the subscription, the smallest offered depth covering `depth`
+    subscribed_depth: usize,
+    // Kraken computes the checksum over prices and quantities formatted with the pair's
+    // precision, which is taken from the listing's tick and lot size. Numbers in messages
+    // lack trailing zeros and cannot be used to derive it
+    price_scale: u32,
+    qty_scale: u32,
+}
+
+impl KrakenOrderbookWsApi {
+    pub fn new(depth: usize) -> Self {
+        let subscribed_depth = KRAKEN_BOOK_DEPTHS
+            .into_iter()
+            .find(|offered| *offered >= depth)
+            .unwrap_or(KRAKEN_BOOK_DEPTHS[KRAKEN_BOOK_DEPTHS.len() - 1]);
+        Self {
+            book: LocalBook::default(),
+            snapshot_received: false,
+            depth,
+            subscribed_depth,
+            price_scale: 0,
+            qty_scale: 0,
+        }
+    }
+
+    fn apply(&mut self, data: &BookMessageData) {
+        for level in data.bids.iter().map(BookLevel::from) {
+            self.book.update_bid(level);
+        }
+        for level in data.asks.iter().map(BookLevel::from) {
+            self.book.update_ask(level);
+        }
+        // Kraken does not send deletions for levels which move out of the subscribed depth
+        self.book.truncate(self.subscribed_depth);
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        let asks = self.book.asks().take(KRAKEN_CHECKSUM_DEPTH);
+        let bids = self.book.bids().take(KRAKEN_CHECKSUM_DEPTH);
+        for level in asks.chain(bids) {
+            hasher.update(checksum_field(level.price, self.price_scale).as_bytes());
+            hasher.update(checksum_field(level.quantity, self.qty_scale).as_bytes());
+        }
+        hasher.finalize()
+    }
+}
+
+/// Formats `value` with `scale` decimals, then removes the decimal point and leading zeros
+fn checksum_field(value: Decimal, scale: u32) -> String {
+    let formatted = format!("{:.*}", scale as usize, value);
+    formatted
+        .replace('.', "")
+        .trim_start_matches('0')
+        .to_string()
+}
+
+impl OrderbookWsApi for KrakenOrderbookWsApi {
+    fn native_symbol(instrument: &Instrument) -> String {
+        instrument.name()
+    }
+
+    fn configure(&mut self, listing: &Listing) {
+        self.price_scale = listing.tick_size.normalize().scale();
+        self.qty_scale = listing.lot_size.normalize().scale();
+    }
+
+    fn subscription_message(&self, symbol: &str) -> String {
+        JSONParser::to_string(&BookSubRequest::new(symbol, self.subscribed_depth)).unwrap()
+    }
+
+    fn unsubscription_message(&self, symbol: &str) -> Option<String> {
+        JSONParser::to_string(&BookSubRequest::unsubscribe(symbol, self.subscribed_depth)).ok()
+    }
+
+    fn verify_confirmation(symbol: &str, message: &str) -> bool {
+        match JSONParser::from_str::<BookSubRequestResponse>(message) {
+            Ok(x) => {
+                x.method.as_deref() == Some("subscribe")
+                    && x.success == Some(true)
+                    && x.result
+                        .is_some_and(|result| result.channel == "book" && result.symbol == symbol)
+            }
+            Err(e) => {
+                error!(target : "KrakenFeed", "Unexpected json {message}; {e:?}");
+                false
+            }
+        }
+    }
+
+    fn subscription_error(message: &str) -> Option<String> {
+        let response = JSONParser::from_str::<BookSubRequestResponse>(message).ok()?;
+        if response.method.as_deref() == Some("subscribe") && response.success == Some(false) {
+            return Some(response.error.unwrap_or_default());
+        }
+        None
+    }
+
+    fn handle_message(&mut self, msg: &str) -> WebsocketResult<Option<Orderbook>> {
+        let message: ChannelMessage = JSONParser::from_str(msg)?;
+        // Heartbeats, status updates and method responses do not carry book data
+        let (Some("book"), Some(data)) = (message.channel, message.data) else {
+            return Ok(None);
+        };
+        let updates: SmallVec<[BookMessageData; 1]> = JSONParser::from_str(data.get())?;
+        if message.kind == Some("snapshot") {
+            self.book.clear();
+            self.snapshot_received = true;
+        } else if !self.snapshot_received {
+            return Ok(None);
+        }
+        for update in updates.iter() {
+            self.apply(update);
+            let computed = self.checksum();
+            if computed != update.checksum {
+                return Err(WebsocketError::ChecksumMismatch {
+                    expected: update.checksum,
+                    computed,
+                });
+            }
+        }
+        let book = self.book.to_orderbook(self.depth);
+        let exchange_time_us = updates
+            .last()
+            .and_then(|update| update.timestamp.as_deref())
+            .and_then(parse_utc_time_us);
+        Ok(Some(match exchange_time_us {
+            Some(time_us) => book.with_exchange_time(time_us),
+            None => book,
+        }))
+    }
+
+    fn connection_endpoint() -> Url {
+        Url::parse("wss://ws.kraken.com/v2").unwrap()
+    }
+
+    fn exchange() -> Exchange {
+        Exchange::Kraken
+    }
+
+    fn heartbeat_message() -> Option<String> {
+        Some(JSONParser::to_string(&PingRequest::default()).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::error::WebsocketError;
+    use crate::defines::instrument::InstrumentRegistry;
+    use crate::defines::Exchange;
+    use crate::feed::exchanges::kraken::{checksum_field, KrakenOrderbookWsApi};
+    use crate::feed::mock_servers::{serve_frames, serve_frames_recording};
+    use crate::feed::ws_api_feed::{OrderbookWebsocket, OrderbookWsApi};
+    use rust_decimal_macros::dec;
+
+    const CONFIRMATION: &str =
+        r#"{"method":"subscribe","success":true,"result":{"channel":"book","symbol":"BTC/USD"}}"#;
+
+    // Numbers are sent without trailing zeros, the checksum uses the listing's precision
+    fn snapshot(checksum: u32) -> String {
+        format!(
+            r#"{{"channel":"book","type":"snapshot","data":[{{"symbol":"BTC/USD",
+            "bids":[{{"price":100.5,"qty":0.5}},{{"price":100,"qty":1.25}}],
+            "asks":[{{"price":101,"qty":0.000005}},{{"price":101.5,"qty":2.12345678}}],
+            "checksum":{checksum}}}]}}"#
+        )
+    }
+
+    fn expected_checksum(fields: &[&str]) -> u32 {
+        crc32fast::hash(fields.concat().as_bytes())
+    }
+
+    // asks ascending then bids descending, price scale 1 and quantity scale 8
+    fn snapshot_checksum() -> u32 {
+        expected_checksum(&[
+            "1010",
+            "500",
+            "1015",
+            "212345678",
+            "1005",
+            "50000000",
+            "1000",
+            "125000000",
+        ])
+    }
+
+    /// Adapter configured for the default BTC/USD listing
+    fn btc_usd_api() -> KrakenOrderbookWsApi {
+        let registry = InstrumentRegistry::with_defaults();
+        let instrument = registry.resolve("BTC/USD").unwrap();
+        let mut api = KrakenOrderbookWsApi::new(10);
+        api.configure(instrument.listing(Exchange::Kraken).unwrap());
+        api
+    }
+
+    #[test]
+    fn precision_from_listing() {
+        let registry = InstrumentRegistry::with_defaults();
+        let instrument = registry.resolve("BTC/USD").unwrap();
+        let mut api = KrakenOrderbookWsApi::new(10);
+        api.configure(instrument.listing(Exchange::Kraken).unwrap());
+        assert_eq!(KrakenOrderbookWsApi::native_symbol(instrument), "BTC/USD");
+        assert_eq!((api.price_scale, api.qty_scale), (1, 8));
+    }
+
+    #[test]
+    fn checksum_field_formatting() {
+        assert_eq!(checksum_field(dec!(0.05005), 5), "5005");
+        assert_eq!(checksum_field(dec!(0.000005), 8), "500");
+        assert_eq!(checksum_field(dec!(100), 1), "1000");
+    }
+
+    #[test]
+    fn snapshot_and_update_checksums() {
+        let mut api = btc_usd_api();
+        let book = api
+            .handle_message(&snapshot(snapshot_checksum()))
+            .unwrap()
+            .unwrap();
+        // Snapshots carry no event time
+        assert_eq!(book.exchange_time_us(), None);
+        assert_eq!(api.book.bids().next().unwrap().price, dec!(100.5));
+
+        let checksum =
+            expected_checksum(&["1015", "212345678", "1005", "50000000", "1000", "125000000"]);
+        let update = format!(
+            r#"{{"channel":"book","type":"update","data":[{{"symbol":"BTC/USD","bids":[],
+            "asks":[{{"price":101.0,"qty":0}}],"checksum":{checksum},
+            "timestamp":"2023-06-20T10:00:00.100000Z"}}]}}"#
+        );
+        let book = api.handle_message(&update).unwrap().unwrap();
+        assert_eq!(book.exchange_time_us(), Some(1_687_255_200_100_000));
+        assert_eq!(api.book.asks().next().unwrap().price, dec!(101.5));
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_forces_resubscribe() {
+        let api = btc_usd_api();
+        let endpoint = serve_frames(vec![
+            CONFIRMATION.to_string(),
+            r#"{"channel":"heartbeat"}"#.to_string(),
+            snapshot(42),
+        ])
+        .await;
+        let mut ws = OrderbookWebsocket::connect_and_subscribe_to(api.clone(), endpoint, "BTC/USD")
+            .await
+            .unwrap();
+        let error = ws.next_book().await.unwrap_err();
+        assert!(matches!(
+            error,
+            WebsocketError::ChecksumMismatch { expected: 42, .. }
+        ));
+        assert!(error.is_retryable());
+
+        // The feed reconnects with a fresh copy of the configured adapter, which subscribes
+        // again and rebuilds the book from the new snapshot
+        let (endpoint, mut client_messages) = serve_frames_recording(vec![
+            CONFIRMATION.to_string(),
+            snapshot(snapshot_checksum()),
+        ])
+        .await;
+        let mut ws = OrderbookWebsocket::connect_and_subscribe_to(api.clone(), endpoint, "BTC/USD")
+            .await
+            .unwrap();
+        let book = ws.next_book().await.unwrap();
+        assert_eq!(book.bids()[0].price, dec!(100.5));
+        assert_eq!(
+            client_messages.recv().await.unwrap().to_text().unwrap(),
+            api.subscription_message("BTC/USD")
+        );
+    }
+}
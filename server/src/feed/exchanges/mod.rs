@@ -1,2 +1,4 @@
 pub(in crate::feed) mod binance;
 pub(in crate::feed) mod bitstamp;
+pub(in crate::feed) mod coinbase;
+pub(in crate::feed) mod kraken;
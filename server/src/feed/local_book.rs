@@ -0,0 +1,70 @@
+use crate::marketdata::{BookLevel, Orderbook};
+use rust_decimal::Decimal;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+/// Full depth book maintained from incremental exchange updates
+#[derive(Debug, Clone, Default)]
+pub(in crate::feed) struct LocalBook {
+    bids: BTreeMap<Reverse<Decimal>, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+}
+
+impl LocalBook {
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+    /// Sets quantity of the bid at `level.price`, a zero quantity removes the level
+    pub fn update_bid(&mut self, level: BookLevel) {
+        if level.quantity.is_zero() {
+            self.bids.remove(&Reverse(level.price));
+        } else {
+            self.bids.insert(Reverse(level.price), level.quantity);
+        }
+    }
+    /// Sets quantity of the ask at `level.price`, a zero quantity removes the level
+    pub fn update_ask(&mut self, level: BookLevel) {
+        if level.quantity.is_zero() {
+            self.asks.remove(&level.price);
+        } else {
+            self.asks.insert(level.price, level.quantity);
+        }
+    }
+    /// Drops all levels beyond `depth` on both sides
+    pub fn truncate(&mut self, depth: usize) {
+        while self.bids.len() > depth {
+            self.bids.pop_last();
+        }
+        while self.asks.len() > depth {
+            self.asks.pop_last();
+        }
+    }
+    /// Bids ordered from best (highest) to worst
+    pub fn bids(&self) -> impl Iterator<Item = BookLevel> + '_ {
+        self.bids.iter().map(|(price, quantity)| BookLevel {
+            price: price.0,
+            quantity: *quantity,
+        })
+    }
+    /// Asks ordered from best (lowest) to worst
+    pub fn asks(&self) -> impl Iterator<Item = BookLevel> + '_ {
+        self.asks.iter().map(|(price, quantity)| BookLevel {
+            price: *price,
+            quantity: *quantity,
+        })
+    }
+    pub fn is_crossed(&self) -> bool {
+        match (self.bids.first_key_value(), self.asks.first_key_value()) {
+            (Some((bid, _)), Some((ask, _))) => bid.0 >= *ask,
+            _ => false,
+        }
+    }
+    /// Best `depth` levels of both sides
+    pub fn to_orderbook(&self, depth: usize) -> Orderbook {
+        Orderbook::new(
+            self.bids().take(depth).collect(),
+            self.asks().take(depth).collect(),
+        )
+    }
+}
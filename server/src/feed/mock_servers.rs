@@ -0,0 +1,63 @@
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+/// Starts a local websocket server which accepts a single connection, waits for the
+/// subscription message and then replays `frames` as text messages
+pub(crate) async fn serve_frames(frames: Vec<String>) -> Url {
+    serve_frames_recording(frames).await.0
+}
+
+/// Same as `serve_frames`, all messages sent by the client are forwarded to the receiver
+pub(crate) async fn serve_frames_recording(
+    frames: Vec<String>,
+) -> (Url, UnboundedReceiver<Message>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        let (tcp_stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(tcp_stream).await.unwrap();
+        if let Some(Ok(subscription)) = ws.next().await {
+            let _ = sender.send(subscription);
+        }
+        for frame in frames {
+            if ws.send(Message::text(frame)).await.is_err() {
+                return;
+            }
+        }
+        // Keep the connection open until the client goes away
+        while let Some(Ok(message)) = ws.next().await {
+            let _ = sender.send(message);
+        }
+    });
+    (Url::parse(&format!("ws://{address}")).unwrap(), receiver)
+}
+
+/// Starts a local HTTP server which answers every request with `body`
+pub(crate) async fn serve_http(body: String) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut tcp_stream, _)) = listener.accept().await {
+            // The request itself is irrelevant, only wait for the end of its headers
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match tcp_stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buffer[..n]),
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = tcp_stream.write_all(response.as_bytes()).await;
+        }
+    });
+    Url::parse(&format!("http://{address}/api/v3/depth")).unwrap()
+}
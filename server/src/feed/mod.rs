@@ -1,32 +1,217 @@
 mod exchanges;
+mod local_book;
+#[cfg(test)]
+mod mock_servers;
 mod orderbook_feed;
+mod reconnect;
+mod recorder;
+mod replay;
+mod status;
 mod ws_api_feed;
 
 use crate::defines::book_callback::BookCallback;
+use crate::defines::instrument::Instrument;
 use crate::defines::Exchange;
-use crate::feed::exchanges::binance::BinanceOrderbookWsApi;
-use crate::feed::exchanges::bitstamp::BitstampOrderbookWsApi;
-use crate::feed::orderbook_feed::ExchangeOrderbookFeed;
+use crate::feed::exchanges::binance::{
+    BinanceDiffOrderbookWsApi, BinanceOrderbookWsApi, DiffUpdateSpeed,
+};
+use crate::feed::exchanges::bitstamp::{BitstampDiffOrderbookWsApi, BitstampOrderbookWsApi};
+use crate::feed::exchanges::coinbase::CoinbaseOrderbookWsApi;
+use crate::feed::exchanges::kraken::KrakenOrderbookWsApi;
+use crate::feed::orderbook_feed::{listed_symbol, ExchangeOrderbookFeed};
+use crate::feed::replay::{ReplayConnection, ReplayOrderbookFeed};
+use crate::feed::ws_api_feed::OrderbookWsApi;
+use log::warn;
+use std::sync::Arc;
+use url::Url;
+
+pub(crate) use reconnect::ReconnectPolicy;
+#[cfg(test)]
+pub(crate) use recorder::CaptureEvent;
+pub(crate) use recorder::{read_capture, CaptureConfig, CaptureRecord, CaptureRecorder};
+pub(crate) use replay::{CaptureReplay, ReplayConfig, ReplayOutput, ReplaySpeed, ReplayStream};
+pub(crate) use status::FeedStatusTracker;
 
 #[async_trait::async_trait]
 pub trait OrderbookFeed: Sync + Send {
-    async fn start(&mut self, symbol: &str);
+    async fn start(&mut self, instrument: &Instrument);
+    /// Unsubscribes, closes the connection and waits until the feed has finished
+    async fn stop(&mut self);
+    /// Stops the feed and starts it again for the last instrument
+    async fn restart(&mut self);
+}
+
+/// How the Binance book is obtained
+#[derive(Debug, Copy, Clone, Default, clap::ValueEnum)]
+pub enum BinanceBookMode {
+    /// `@depth<N>@100ms` partial book snapshots, at most 20 levels
+    #[default]
+    Partial,
+    /// `@depth@100ms` diff stream synchronised with a REST snapshot
+    Diff100ms,
+    /// `@depth` diff stream synchronised with a REST snapshot
+    Diff1000ms,
+}
+
+/// How the Bitstamp book is obtained
+#[derive(Debug, Copy, Clone, Default, clap::ValueEnum)]
+pub enum BitstampBookMode {
+    /// `order_book` channel with the top 100 levels in every message
+    #[default]
+    Snapshot,
+    /// `diff_order_book` channel applied to a REST order book snapshot
+    Diff,
+}
+
+/// Exchange specific options of the orderbook feeds
+#[derive(Debug, Clone)]
+pub(crate) struct FeedConfig {
+    pub binance_mode: BinanceBookMode,
+    /// REST depth endpoint used to synchronise the Binance diff stream
+    pub binance_snapshot_endpoint: Url,
+    pub bitstamp_mode: BitstampBookMode,
+    /// REST order book endpoint used to initialise the Bitstamp diff channel
+    pub bitstamp_snapshot_endpoint: Url,
+    pub reconnect: ReconnectPolicy,
+    /// Levels per side kept of every exchange's book, also the maximum depth of summaries
+    pub depth: usize,
+    /// Captures the websocket traffic of all feeds if set
+    pub recorder: Option<Arc<CaptureRecorder>>,
+    /// Feeds replay this capture instead of connecting to the exchanges if set
+    pub replay: Option<Arc<CaptureReplay>>,
+    /// Reconnects feeds without a book update within the exchange's inactivity timeout.
+    /// Off for quiet books such as conversion rates, dead connections are still detected
+    /// by pings
+    pub book_timeout: bool,
+}
+
+impl Default for FeedConfig {
+    fn default() -> Self {
+        Self {
+            binance_mode: BinanceBookMode::default(),
+            binance_snapshot_endpoint: Url::parse("https://api.binance.com/api/v3/depth").unwrap(),
+            bitstamp_mode: BitstampBookMode::default(),
+            bitstamp_snapshot_endpoint: Url::parse("https://www.bitstamp.net/api/v2/order_book/")
+                .unwrap(),
+            reconnect: ReconnectPolicy::default(),
+            depth: 10,
+            recorder: None,
+            replay: None,
+            book_timeout: true,
+        }
+    }
+}
+
+/// Consumes the API which the feed configuration selects for an exchange
+trait WithApi {
+    type Output;
+    fn with<ExchangeApi: OrderbookWsApi>(self, api: ExchangeApi) -> Self::Output;
+}
+
+/// Live feed of the API, or its replay if a capture is configured
+struct FeedOf<'a, T: BookCallback + Clone> {
+    config: &'a FeedConfig,
+    callback: T,
+    status: Arc<FeedStatusTracker>,
+}
+
+impl<'a, T: BookCallback + Clone> WithApi for FeedOf<'a, T> {
+    type Output = Box<dyn OrderbookFeed>;
+    fn with<ExchangeApi: OrderbookWsApi>(self, api: ExchangeApi) -> Self::Output {
+        let mut keep_alive = ExchangeApi::keep_alive();
+        if !self.config.book_timeout {
+            keep_alive.inactivity_timeout = None;
+        }
+        match &self.config.replay {
+            Some(replay) => Box::new(ReplayOrderbookFeed::new(
+                api,
+                self.callback,
+                self.status,
+                replay.clone(),
+            )),
+            None => Box::new(ExchangeOrderbookFeed::new(
+                api,
+                self.callback,
+                self.status,
+                self.config.reconnect,
+                keep_alive,
+                self.config.recorder.clone(),
+            )),
+        }
+    }
+}
+
+/// Captured stream of the instrument, `None` if it is not listed on the API's exchange
+struct ReplayStreamOf<'a> {
+    instrument: &'a Instrument,
+}
+
+impl<'a> WithApi for ReplayStreamOf<'a> {
+    type Output = Option<Box<dyn ReplayStream>>;
+    fn with<ExchangeApi: OrderbookWsApi>(self, mut api: ExchangeApi) -> Self::Output {
+        let symbol = listed_symbol(&mut api, self.instrument)?;
+        Some(Box::new(ReplayConnection::new(api, &symbol)))
+    }
 }
 
 pub struct OrderbookFeedFactory {}
 
 impl OrderbookFeedFactory {
-    pub(crate) fn create_feed<T: BookCallback>(
+    pub(crate) fn create_feed<T: BookCallback + Clone>(
         exchange: Exchange,
+        config: &FeedConfig,
         callback: T,
+        status: Arc<FeedStatusTracker>,
     ) -> Box<dyn OrderbookFeed> {
-        match exchange {
-            Exchange::Binance => Box::new(ExchangeOrderbookFeed::<BinanceOrderbookWsApi, T>::new(
+        Self::with_api(
+            exchange,
+            config,
+            FeedOf {
+                config,
                 callback,
-            )),
-            Exchange::Bitstamp => Box::new(
-                ExchangeOrderbookFeed::<BitstampOrderbookWsApi, T>::new(callback),
-            ),
+                status,
+            },
+        )
+    }
+    /// Stream of `instrument` in a capture, handled by the same API as the live feed
+    pub(crate) fn create_replay_stream(
+        exchange: Exchange,
+        config: &FeedConfig,
+        instrument: &Instrument,
+    ) -> Option<Box<dyn ReplayStream>> {
+        Self::with_api(exchange, config, ReplayStreamOf { instrument })
+    }
+    fn with_api<C: WithApi>(exchange: Exchange, config: &FeedConfig, consumer: C) -> C::Output {
+        match exchange {
+            Exchange::Binance => {
+                let update_speed = match config.binance_mode {
+                    BinanceBookMode::Partial => {
+                        if config.depth > BinanceOrderbookWsApi::max_depth() {
+                            let max_depth = BinanceOrderbookWsApi::max_depth();
+                            warn!(target : "OrderbookFeed", "Binance partial books are limited to {max_depth} levels");
+                        }
+                        return consumer.with(BinanceOrderbookWsApi::new(config.depth));
+                    }
+                    BinanceBookMode::Diff100ms => DiffUpdateSpeed::Ms100,
+                    BinanceBookMode::Diff1000ms => DiffUpdateSpeed::Ms1000,
+                };
+                consumer.with(BinanceDiffOrderbookWsApi::new(
+                    config.binance_snapshot_endpoint.clone(),
+                    update_speed,
+                    config.depth,
+                ))
+            }
+            Exchange::Bitstamp => match config.bitstamp_mode {
+                BitstampBookMode::Snapshot => {
+                    consumer.with(BitstampOrderbookWsApi::new(config.depth))
+                }
+                BitstampBookMode::Diff => consumer.with(BitstampDiffOrderbookWsApi::new(
+                    config.bitstamp_snapshot_endpoint.clone(),
+                    config.depth,
+                )),
+            },
+            Exchange::Kraken => consumer.with(KrakenOrderbookWsApi::new(config.depth)),
+            Exchange::Coinbase => consumer.with(CoinbaseOrderbookWsApi::new(config.depth)),
         }
     }
 }
@@ -1,65 +1,190 @@
 use crate::defines::book_callback::BookCallback;
-use crate::feed::ws_api_feed::{OrderbookWebsocket, OrderbookWsApi};
-use crate::feed::OrderbookFeed;
-use log::info;
-use std::marker::PhantomData;
-use std::time::Duration;
+use crate::defines::grpc_scheme::FeedState;
+use crate::defines::instrument::Instrument;
+use crate::feed::reconnect::Backoff;
+use crate::feed::recorder::{CaptureEvent, CaptureRecorder, StreamRecorder};
+use crate::feed::status::FeedStatusHandle;
+use crate::feed::ws_api_feed::{KeepAlive, OrderbookWebsocket, OrderbookWsApi};
+use crate::feed::{FeedStatusTracker, OrderbookFeed, ReconnectPolicy};
+use log::{error, info, warn};
+use std::sync::Arc;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 
-pub(in crate::feed) struct ExchangeOrderbookFeed<ExchangeApi: OrderbookWsApi, T: BookCallback> {
+/// Symbol of `instrument` on the exchange of `api`, which is configured for the listing.
+/// `None` if the instrument is not listed there
+pub(in crate::feed) fn listed_symbol<ExchangeApi: OrderbookWsApi>(
+    api: &mut ExchangeApi,
+    instrument: &Instrument,
+) -> Option<String> {
+    let exchange = ExchangeApi::exchange();
+    let Some(listing) = instrument.listing(exchange) else {
+        info!(target : "OrderbookFeed", "{} is not listed on {}", instrument.name(), exchange.name());
+        return None;
+    };
+    api.configure(listing);
+    Some(
+        listing
+            .native_symbol
+            .clone()
+            .unwrap_or_else(|| ExchangeApi::native_symbol(instrument)),
+    )
+}
+
+pub(in crate::feed) struct ExchangeOrderbookFeed<
+    ExchangeApi: OrderbookWsApi,
+    T: BookCallback + Clone,
+> {
     handle: Option<JoinHandle<()>>,
-    phantom: PhantomData<ExchangeApi>,
-    callback: Option<T>,
+    // Cancels the task of `handle`
+    cancellation: CancellationToken,
+    api: ExchangeApi,
+    callback: T,
+    status: Arc<FeedStatusTracker>,
+    // Status entry of the feed, registered on the first start
+    status_handle: Option<FeedStatusHandle>,
+    reconnect: ReconnectPolicy,
+    keep_alive: KeepAlive,
+    // Instrument of the last start, used by restart
+    instrument: Option<Instrument>,
+    recorder: Option<Arc<CaptureRecorder>>,
 }
 
-impl<ExchangeApi: OrderbookWsApi, T: BookCallback> ExchangeOrderbookFeed<ExchangeApi, T> {
-    pub fn new(callback: T) -> Self {
+impl<ExchangeApi: OrderbookWsApi, T: BookCallback + Clone> ExchangeOrderbookFeed<ExchangeApi, T> {
+    pub fn new(
+        api: ExchangeApi,
+        callback: T,
+        status: Arc<FeedStatusTracker>,
+        reconnect: ReconnectPolicy,
+        keep_alive: KeepAlive,
+        recorder: Option<Arc<CaptureRecorder>>,
+    ) -> Self {
         Self {
             handle: None,
-            phantom: Default::default(),
-            callback: Some(callback),
+            cancellation: CancellationToken::new(),
+            api,
+            callback,
+            status,
+            status_handle: None,
+            reconnect,
+            keep_alive,
+            instrument: None,
+            recorder,
         }
     }
 }
 
 #[async_trait::async_trait]
-impl<ExchangeApi: OrderbookWsApi, T: BookCallback> OrderbookFeed
+impl<ExchangeApi: OrderbookWsApi, T: BookCallback + Clone> OrderbookFeed
     for ExchangeOrderbookFeed<ExchangeApi, T>
 {
-    async fn start(&mut self, symbol: &str) {
-        let symbol = String::from(symbol);
+    async fn start(&mut self, instrument: &Instrument) {
+        let exchange = ExchangeApi::exchange();
         // Check if already running
-        if self.handle.is_some() || self.callback.is_none() {
+        if self.handle.is_some() {
             return;
         }
-        let sender = self.callback.take().unwrap();
+        let Some(symbol) = listed_symbol(&mut self.api, instrument) else {
+            return;
+        };
+        self.instrument = Some(instrument.clone());
+        let sender = self.callback.clone();
+        let api = self.api.clone();
+        let status = self
+            .status_handle
+            .get_or_insert_with(|| self.status.register(exchange, &symbol))
+            .clone();
+        let recorder = self
+            .recorder
+            .clone()
+            .map(|recorder| StreamRecorder::new(recorder, exchange, &symbol));
+        let keep_alive = self.keep_alive;
+        let mut backoff = Backoff::new(self.reconnect);
+        self.cancellation = CancellationToken::new();
+        let cancellation = self.cancellation.clone();
         let handle = tokio::spawn(async move {
-            let exchange = ExchangeApi::exchange();
             loop {
-                let mut ws =
-                    match OrderbookWebsocket::<ExchangeApi>::connect_and_subscribe(&symbol).await {
-                        Ok(ws) => ws,
-                        Err(e) => {
-                            info!(target : "OrderbookFeed", "Unexpected error {e:?}");
-                            // wait 1s to not get rate limited
-                            sleep(Duration::from_secs(1)).await;
-                            continue;
-                        }
-                    };
-                loop {
-                    match ws.next_book().await {
-                        Ok(book) => {
-                            sender.accept_book(book, exchange).await;
-                        }
-                        Err(e) => {
-                            info!(target : "OrderbookFeed", "Unexpected error {e:?}");
-                            break;
+                status.set_state(FeedState::Connecting);
+                let connection = tokio::select! {
+                    _ = cancellation.cancelled() => break,
+                    connection = OrderbookWebsocket::connect_and_subscribe(api.clone(), &symbol, keep_alive, recorder.clone()) => connection,
+                };
+                let error = match connection {
+                    Ok(mut ws) => {
+                        status.set_state(FeedState::Subscribed);
+                        let error = loop {
+                            let book = tokio::select! {
+                                _ = cancellation.cancelled() => break None,
+                                book = ws.next_book() => book,
+                            };
+                            match book {
+                                Ok(book) => {
+                                    backoff.succeeded();
+                                    status.book_received();
+                                    sender.accept_book(book, exchange).await;
+                                }
+                                Err(e) => break Some(e),
+                            }
+                        };
+                        sender.disconnected(exchange).await;
+                        match error {
+                            Some(error) => error,
+                            None => {
+                                ws.close().await;
+                                if let Some(recorder) = &recorder {
+                                    recorder
+                                        .record(CaptureEvent::Disconnected("stopped".to_string()));
+                                }
+                                break;
+                            }
                         }
                     }
+                    Err(e) => e,
+                };
+                if let Some(recorder) = &recorder {
+                    recorder.record(CaptureEvent::Disconnected(error.to_string()));
+                }
+                if !error.is_retryable() {
+                    error!(target : "OrderbookFeed", "Stopping {exchange:?} feed of {symbol}: {error}");
+                    status.stopped(&error);
+                    return;
+                }
+                let delay = if error.is_rate_limited() {
+                    let delay = backoff.rate_limited();
+                    warn!(target : "OrderbookFeed", "Rate limited by {exchange:?}: {error}, reconnecting in {delay:?}");
+                    delay
+                } else {
+                    let delay = backoff.failed();
+                    info!(target : "OrderbookFeed", "Unexpected error {error:?}, reconnecting to {exchange:?} in {delay:?}");
+                    delay
+                };
+                status.backing_off(&error, delay, backoff.is_circuit_open());
+                // wait to not get rate limited
+                tokio::select! {
+                    _ = cancellation.cancelled() => break,
+                    _ = sleep(delay) => {},
                 }
             }
+            info!(target : "OrderbookFeed", "Stopped {exchange:?} feed of {symbol}");
+            status.set_state(FeedState::Stopped);
         });
         self.handle = Some(handle);
     }
+
+    async fn stop(&mut self) {
+        self.cancellation.cancel();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.await {
+                error!(target : "OrderbookFeed", "{:?} feed task failed: {e}", ExchangeApi::exchange());
+            }
+        }
+    }
+
+    async fn restart(&mut self) {
+        self.stop().await;
+        if let Some(instrument) = self.instrument.clone() {
+            self.start(&instrument).await;
+        }
+    }
 }
@@ -0,0 +1,123 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Delays between connection attempts of a feed
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Factor the delay grows by with every consecutive failure
+    pub multiplier: f64,
+    /// Fraction of the delay which is randomised, 0 disables jitter
+    pub jitter: f64,
+    /// Consecutive failures after which the circuit opens, 0 disables the circuit breaker
+    pub failure_threshold: u32,
+    /// Delay while the circuit is open, afterwards a single attempt is made
+    pub open_duration: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.,
+            jitter: 0.2,
+            failure_threshold: 10,
+            open_duration: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Reconnect state of a single feed
+#[derive(Debug)]
+pub(in crate::feed) struct Backoff {
+    policy: ReconnectPolicy,
+    consecutive_failures: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            consecutive_failures: 0,
+        }
+    }
+    /// True while attempts are suspended because of too many consecutive failures.
+    /// The first attempt after `open_duration` either closes the circuit or opens it again
+    pub fn is_circuit_open(&self) -> bool {
+        self.policy.failure_threshold > 0
+            && self.consecutive_failures >= self.policy.failure_threshold
+    }
+    /// Resets the delay once a connection delivered data
+    pub fn succeeded(&mut self) {
+        self.consecutive_failures = 0;
+    }
+    /// Returns the delay before the next attempt after the exchange rejected the connection
+    /// because of rate limiting, which is at least as long as the open circuit's delay
+    pub fn rate_limited(&mut self) -> Duration {
+        self.failed().max(self.policy.open_duration)
+    }
+    /// Returns the delay before the next attempt
+    pub fn failed(&mut self) -> Duration {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.is_circuit_open() {
+            return self.policy.open_duration;
+        }
+        let exponent = (self.consecutive_failures - 1).min(i32::MAX as u32) as i32;
+        let delay = self
+            .policy
+            .initial_delay
+            .mul_f64(self.policy.multiplier.powi(exponent).min(u32::MAX as f64))
+            .min(self.policy.max_delay);
+        // Attempts of feeds which failed at the same time should not stay synchronised
+        let jitter = self.policy.jitter.clamp(0., 1.);
+        if jitter > 0. {
+            delay.mul_f64(1. - jitter * rand::thread_rng().gen::<f64>())
+        } else {
+            delay
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::feed::reconnect::{Backoff, ReconnectPolicy};
+    use std::time::Duration;
+
+    #[test]
+    fn delay_grows_until_circuit_opens() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            multiplier: 2.,
+            jitter: 0.,
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(60),
+        };
+        let mut backoff = Backoff::new(policy);
+        let delays: Vec<u128> = (0..4).map(|_| backoff.failed().as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 500]);
+        assert!(!backoff.is_circuit_open());
+        assert_eq!(backoff.failed(), Duration::from_secs(60));
+        assert!(backoff.is_circuit_open());
+        // The attempt after the open period failed as well
+        assert_eq!(backoff.failed(), Duration::from_secs(60));
+
+        backoff.succeeded();
+        assert!(!backoff.is_circuit_open());
+        assert_eq!(backoff.failed(), Duration::from_millis(100));
+        assert_eq!(backoff.rate_limited(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn jitter_only_shortens_delay() {
+        let mut backoff = Backoff::new(ReconnectPolicy::default());
+        for _ in 0..100 {
+            let delay = backoff.failed();
+            backoff.succeeded();
+            assert!(delay <= Duration::from_millis(500));
+            assert!(delay >= Duration::from_millis(400));
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use crate::defines::Exchange;
+use crate::helper::unix_time_us;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Where and how long websocket traffic is captured
+#[derive(Debug, Clone)]
+pub(crate) struct CaptureConfig {
+    pub directory: PathBuf,
+    /// A new file is started once this many uncompressed bytes were written
+    pub max_file_bytes: u64,
+    /// A new file is started once the current one is this old
+    pub max_file_age: Duration,
+    /// Oldest files are deleted when there are more, 0 keeps all files
+    pub max_files: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("capture"),
+            max_file_bytes: 256 * 1024 * 1024,
+            max_file_age: Duration::from_secs(3600),
+            max_files: 0,
+        }
+    }
+}
+
+/// Single line of a capture file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CaptureRecord {
+    /// Receive time of frames, otherwise the time of the event, in microseconds since the
+    /// unix epoch
+    pub time_us: u64,
+    pub exchange: Exchange,
+    pub symbol: String,
+    #[serde(flatten)]
+    pub event: CaptureEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub(crate) enum CaptureEvent {
+    /// Subscription message sent to the exchange
+    Subscribe(String),
+    /// Exchange confirmed the subscription
+    Subscribed,
+    /// Text frame received from the exchange
+    Frame(String),
+    /// REST book snapshot the stream was synchronised with
+    Snapshot(String),
+    /// Connection ended, with the reason
+    Disconnected(String),
+}
+
+enum Command {
+    Record(CaptureRecord),
+    Close,
+}
+
+/// Writes the captured traffic of all feeds to rotating gzip compressed JSON lines files.
+/// Files are written by a dedicated thread so feeds never wait for the disk, records are
+/// dropped while the writer cannot keep up
+pub(crate) struct CaptureRecorder {
+    sender: Mutex<Option<mpsc::SyncSender<Command>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    // Records dropped because the queue was full
+    dropped: AtomicU64,
+}
+
+impl std::fmt::Debug for CaptureRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureRecorder").finish_non_exhaustive()
+    }
+}
+
+impl CaptureRecorder {
+    // Records queued for the writer
+    const QUEUE_CAPACITY: usize = 64 * 1024;
+    pub fn start(config: CaptureConfig) -> std::io::Result<Arc<Self>> {
+        std::fs::create_dir_all(&config.directory)?;
+        let (sender, receiver) = mpsc::sync_channel(Self::QUEUE_CAPACITY);
+        let writer = std::thread::Builder::new()
+            .name("capture-writer".to_string())
+            .spawn(move || CaptureWriter::new(config).run(receiver))?;
+        Ok(Arc::new(Self {
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+            dropped: AtomicU64::new(0),
+        }))
+    }
+    pub fn record(&self, record: CaptureRecord) {
+        if let Some(sender) = self.sender.lock().as_ref() {
+            // Disconnected only means that the writer stopped after a failure, which it logged
+            if let Err(TrySendError::Full(_)) = sender.try_send(Command::Record(record)) {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!(target : "CaptureRecorder", "Capture queue is full, dropping records");
+                }
+            }
+        }
+    }
+    /// Records dropped so far because the writer could not keep up
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+    /// Writes all pending records and completes the current file, later records are dropped
+    pub fn close(&self) {
+        if let Some(sender) = self.sender.lock().take() {
+            let _ = sender.send(Command::Close);
+        }
+        if let Some(writer) = self.writer.lock().take() {
+            let _ = writer.join();
+            let dropped = self.dropped();
+            if dropped > 0 {
+                warn!(target : "CaptureRecorder", "{dropped} records were dropped");
+            }
+        }
+    }
+}
+
+impl Drop for CaptureRecorder {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Records the events of a single feed
+#[derive(Debug, Clone)]
+pub(in crate::feed) struct StreamRecorder {
+    recorder: Arc<CaptureRecorder>,
+    exchange: Exchange,
+    symbol: String,
+}
+
+impl StreamRecorder {
+    pub fn new(recorder: Arc<CaptureRecorder>, exchange: Exchange, symbol: &str) -> Self {
+        Self {
+            recorder,
+            exchange,
+            symbol: symbol.to_string(),
+        }
+    }
+    pub fn record(&self, event: CaptureEvent) {
+        self.record_at(unix_time_us(), event);
+    }
+    pub fn record_at(&self, time_us: u64, event: CaptureEvent) {
+        self.recorder.record(CaptureRecord {
+            time_us,
+            exchange: self.exchange,
+            symbol: self.symbol.clone(),
+            event,
+        });
+    }
+}
+
+/// Name of the `index`th capture file started at `unix_time_ms`, names sort by time and
+/// index
+fn capture_file_name(unix_time_ms: u64, index: u64) -> String {
+    format!("capture-{unix_time_ms:013}-{index:06}.jsonl.gz")
+}
+
+/// Capture files in `directory`, oldest first
+pub(crate) fn capture_files(directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("capture-") && name.ends_with(".jsonl.gz"))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Records of all capture files in `directory` in the order they were captured. Damaged
+/// lines are skipped and files which were not completed, e.g. after a crash, are read up
+/// to the damaged part
+pub(crate) fn read_capture(
+    directory: &Path,
+) -> std::io::Result<impl Iterator<Item = CaptureRecord>> {
+    Ok(capture_files(directory)?
+        .into_iter()
+        .flat_map(read_capture_file))
+}
+
+fn read_capture_file(path: PathBuf) -> impl Iterator<Item = CaptureRecord> {
+    let lines = File::open(&path)
+        .map(|file| BufReader::new(GzDecoder::new(file)).lines())
+        .map_err(|e| error!(target : "CaptureRecorder", "Failed to open {path:?}: {e}"))
+        .ok();
+    lines
+        .into_iter()
+        .flatten()
+        .map_while(move |line| {
+            line.map_err(|e| info!(target : "CaptureRecorder", "Stopped reading {path:?}: {e}"))
+                .ok()
+        })
+        .filter_map(|line| {
+            serde_json::from_str(&line)
+                .map_err(|e| info!(target : "CaptureRecorder", "Skipping capture line {line}: {e}"))
+                .ok()
+        })
+}
+
+struct CaptureFile {
+    encoder: GzEncoder<BufWriter<File>>,
+    opened: Instant,
+    written: u64,
+}
+
+struct CaptureWriter {
+    config: CaptureConfig,
+    file: Option<CaptureFile>,
+    // Files opened so far, keeps the names of files opened within the same millisecond apart
+    opened_files: u64,
+}
+
+impl CaptureWriter {
+    // Buffered records are flushed to the file after this long without a new record
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+    fn new(config: CaptureConfig) -> Self {
+        Self {
+            config,
+            file: None,
+            opened_files: 0,
+        }
+    }
+    fn run(mut self, receiver: mpsc::Receiver<Command>) {
+        loop {
+            let result = match receiver.recv_timeout(Self::FLUSH_INTERVAL) {
+                Ok(Command::Record(record)) => self.write(&record),
+                Err(RecvTimeoutError::Timeout) => self.flush(),
+                Ok(Command::Close) | Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(e) = result {
+                error!(target : "CaptureRecorder", "Stopped capturing to {:?}: {e}", self.config.directory);
+                return;
+            }
+        }
+        if let Err(e) = self.finish_file() {
+            error!(target : "CaptureRecorder", "Failed to complete capture file: {e}");
+        }
+    }
+    fn write(&mut self, record: &CaptureRecord) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let rotate = self.file.as_ref().is_some_and(|file| {
+            file.written >= self.config.max_file_bytes
+                || file.opened.elapsed() >= self.config.max_file_age
+        });
+        if rotate {
+            self.finish_file()?;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.open_file()?,
+        };
+        file.encoder.write_all(&line)?;
+        file.written += line.len() as u64;
+        Ok(())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.encoder.flush(),
+            None => Ok(()),
+        }
+    }
+    fn open_file(&mut self) -> std::io::Result<&mut CaptureFile> {
+        let unix_time_ms = unix_time_us() / 1000;
+        // Files of an earlier run may have been opened within the same millisecond
+        let (file, path) = loop {
+            let path = self
+                .config
+                .directory
+                .join(capture_file_name(unix_time_ms, self.opened_files));
+            self.opened_files += 1;
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (file, path),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        info!(target : "CaptureRecorder", "Capturing to {path:?}");
+        self.prune()?;
+        Ok(self.file.insert(CaptureFile {
+            encoder: GzEncoder::new(BufWriter::new(file), Compression::default()),
+            opened: Instant::now(),
+            written: 0,
+        }))
+    }
+    fn finish_file(&mut self) -> std::io::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.encoder.finish()?.flush()?;
+        }
+        Ok(())
+    }
+    /// Deletes the oldest files beyond `max_files`
+    fn prune(&self) -> std::io::Result<()> {
+        if self.config.max_files == 0 {
+            return Ok(());
+        }
+        let files = capture_files(&self.config.directory)?;
+        let excess = files.len().saturating_sub(self.config.max_files);
+        for path in &files[..excess] {
+            info!(target : "CaptureRecorder", "Deleting {path:?}");
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::Exchange;
+    use crate::feed::recorder::{
+        capture_files, read_capture, read_capture_file, CaptureConfig, CaptureEvent, CaptureRecord,
+        CaptureRecorder, StreamRecorder,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn records_are_written_to_rotating_files() {
+        let directory = std::env::temp_dir().join(format!("capture-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let recorder = CaptureRecorder::start(CaptureConfig {
+            directory: directory.clone(),
+            max_file_bytes: 1,
+            max_file_age: Duration::from_secs(3600),
+            max_files: 2,
+        })
+        .unwrap();
+        let stream = StreamRecorder::new(recorder.clone(), Exchange::Kraken, "XBT/USDT");
+        // Files are rotated within the same millisecond
+        stream.record(CaptureEvent::Subscribe("{}".to_string()));
+        stream.record_at(7, CaptureEvent::Frame("[1]".to_string()));
+        stream.record(CaptureEvent::Disconnected("timeout".to_string()));
+        recorder.close();
+        assert_eq!(recorder.dropped(), 0);
+
+        // Every record exceeds the size limit, the first file was deleted
+        let files = capture_files(&directory).unwrap();
+        assert_eq!(files.len(), 2);
+        let frame: Vec<CaptureRecord> = read_capture_file(files[0].clone()).collect();
+        assert_eq!(
+            frame,
+            vec![CaptureRecord {
+                time_us: 7,
+                exchange: Exchange::Kraken,
+                symbol: "XBT/USDT".to_string(),
+                event: CaptureEvent::Frame("[1]".to_string()),
+            }]
+        );
+        let events: Vec<CaptureEvent> = read_capture(&directory)
+            .unwrap()
+            .map(|record| record.event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                CaptureEvent::Frame("[1]".to_string()),
+                CaptureEvent::Disconnected("timeout".to_string())
+            ]
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
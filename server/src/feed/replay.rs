@@ -0,0 +1,469 @@
+use crate::defines::book_callback::BookCallback;
+use crate::defines::error::WebsocketError;
+use crate::defines::grpc_scheme::FeedState;
+use crate::defines::instrument::Instrument;
+use crate::defines::Exchange;
+use crate::feed::orderbook_feed::listed_symbol;
+use crate::feed::recorder::{read_capture, CaptureEvent, CaptureRecord};
+use crate::feed::status::FeedStatusHandle;
+use crate::feed::ws_api_feed::OrderbookWsApi;
+use crate::feed::{FeedStatusTracker, OrderbookFeed};
+use crate::marketdata::Orderbook;
+use log::{error, info};
+use parking_lot::Mutex;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// How fast captured traffic is replayed
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum ReplaySpeed {
+    /// Messages are delivered with the delays they were received with
+    #[default]
+    RealTime,
+    /// Messages are delivered as fast as they are processed
+    Fast,
+}
+
+/// Capture the feeds are replayed from instead of connecting to the exchanges
+#[derive(Debug, Clone)]
+pub(crate) struct ReplayConfig {
+    /// Directory of the capture files
+    pub directory: PathBuf,
+    pub speed: ReplaySpeed,
+}
+
+/// Reads a capture once and dispatches its records to the replayed feeds in capture order.
+/// Delays are measured from the first record of the capture, which keeps the feeds in sync
+pub(crate) struct CaptureReplay {
+    config: ReplayConfig,
+    // Records of the subscribed streams by exchange and symbol
+    streams: Mutex<Vec<(Exchange, String, mpsc::Sender<CaptureRecord>)>>,
+    started: Mutex<bool>,
+}
+
+impl std::fmt::Debug for CaptureReplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureReplay")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CaptureReplay {
+    // Records a feed may lag behind the replay before the replay waits for it
+    const STREAM_CAPACITY: usize = 64;
+    pub fn new(config: ReplayConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            streams: Mutex::new(Vec::new()),
+            started: Mutex::new(false),
+        })
+    }
+    /// Records of the stream of `symbol` on `exchange`, replacing an earlier subscription
+    /// of the stream. The records end with the capture
+    fn subscribe(&self, exchange: Exchange, symbol: &str) -> mpsc::Receiver<CaptureRecord> {
+        let (sender, receiver) = mpsc::channel(Self::STREAM_CAPACITY);
+        let mut streams = self.streams.lock();
+        streams.retain(|(stream_exchange, stream_symbol, _)| {
+            (*stream_exchange, stream_symbol.as_str()) != (exchange, symbol)
+        });
+        streams.push((exchange, symbol.to_string(), sender));
+        receiver
+    }
+    /// Starts the replay once all feeds subscribed, later calls have no effect
+    pub fn start(self: &Arc<Self>) {
+        let mut started = self.started.lock();
+        if *started {
+            return;
+        }
+        *started = true;
+        tokio::spawn(self.clone().run());
+    }
+    async fn run(self: Arc<Self>) {
+        let directory = &self.config.directory;
+        let mut records = match read_records(directory.clone()) {
+            Ok(records) => records,
+            Err(e) => {
+                error!(target : "OrderbookFeed", "Failed to replay {directory:?}: {e}");
+                self.streams.lock().clear();
+                return;
+            }
+        };
+        info!(target : "OrderbookFeed", "Replaying {directory:?}");
+        // Capture time of the first record and when it was replayed
+        let mut origin: Option<(u64, Instant)> = None;
+        while let Some(record) = records.recv().await {
+            let (origin_us, started) = *origin.get_or_insert((record.time_us, Instant::now()));
+            let sender = self
+                .streams
+                .lock()
+                .iter()
+                .find(|(exchange, symbol, _)| {
+                    *exchange == record.exchange && *symbol == record.symbol
+                })
+                .map(|(_, _, sender)| sender.clone());
+            let Some(sender) = sender else {
+                continue;
+            };
+            if self.config.speed == ReplaySpeed::RealTime {
+                // Records of different feeds may be slightly out of order, they are not delayed
+                let offset_us = record.time_us.saturating_sub(origin_us);
+                sleep_until(started + Duration::from_micros(offset_us)).await;
+            }
+            // Fails if the feed was stopped, its records are skipped until it subscribes again
+            let _ = sender.send(record).await;
+        }
+        info!(target : "OrderbookFeed", "Finished replay of {directory:?}");
+        // Ends the records of all feeds
+        self.streams.lock().clear();
+    }
+}
+
+/// Result of a replayed event
+#[derive(Debug)]
+// Nearly every output is a book, boxing it would only add an allocation
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum ReplayOutput {
+    Subscribed,
+    Book(Orderbook),
+    Disconnected,
+}
+
+/// Captured stream of a single feed, independent of the exchange's API
+pub(crate) trait ReplayStream {
+    /// Whether `record` belongs to the replayed stream
+    fn accepts(&self, record: &CaptureRecord) -> bool;
+    fn handle(&mut self, record: CaptureRecord) -> Vec<ReplayOutput>;
+}
+
+/// Feeds the captured events of a single stream through the exchange's API, the same way
+/// `OrderbookWebsocket` handles the live traffic
+pub(in crate::feed) struct ReplayConnection<ExchangeApi: OrderbookWsApi> {
+    // Configured instance, every connection starts with a fresh clone
+    template: ExchangeApi,
+    symbol: String,
+    // API of the current connection, `None` until the subscription was confirmed
+    api: Option<ExchangeApi>,
+    // Frames received while waiting for the book snapshot with their receive time
+    buffered: Option<Vec<(String, u64)>>,
+}
+
+impl<ExchangeApi: OrderbookWsApi> ReplayConnection<ExchangeApi> {
+    pub fn new(template: ExchangeApi, symbol: &str) -> Self {
+        Self {
+            template,
+            symbol: symbol.to_string(),
+            api: None,
+            buffered: None,
+        }
+    }
+    pub fn is_connected(&self) -> bool {
+        self.api.is_some()
+    }
+    fn handle_text(&mut self, text: &str, received_at_us: u64) -> Option<ReplayOutput> {
+        match self.api.as_mut()?.handle_message(text) {
+            Ok(Some(mut book)) => {
+                book.set_received_at(received_at_us);
+                Some(ReplayOutput::Book(book))
+            }
+            Ok(None) => None,
+            Err(WebsocketError::JsonError(e)) => {
+                info!(target : "OrderbookFeed", "Unexpected JSON {text}, error: {e:?}");
+                None
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+    fn fail(&mut self, error: WebsocketError) -> Option<ReplayOutput> {
+        info!(target : "OrderbookFeed", "Replayed {:?} stream of {} failed: {error}", ExchangeApi::exchange(), self.symbol);
+        self.fail_silently().pop()
+    }
+    /// Ends the current connection, books are ignored until the next subscription
+    fn fail_silently(&mut self) -> Vec<ReplayOutput> {
+        self.buffered = None;
+        match self.api.take() {
+            Some(_) => vec![ReplayOutput::Disconnected],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl<ExchangeApi: OrderbookWsApi> ReplayStream for ReplayConnection<ExchangeApi> {
+    fn accepts(&self, record: &CaptureRecord) -> bool {
+        record.exchange == ExchangeApi::exchange() && record.symbol == self.symbol
+    }
+    fn handle(&mut self, record: CaptureRecord) -> Vec<ReplayOutput> {
+        let time_us = record.time_us;
+        match record.event {
+            CaptureEvent::Subscribe(_) => {
+                // Frames before the confirmation only belong to the subscription
+                self.api = None;
+                self.buffered = None;
+                Vec::new()
+            }
+            CaptureEvent::Subscribed => {
+                let api = self.template.clone();
+                self.buffered = api.snapshot_endpoint(&self.symbol).map(|_| Vec::new());
+                self.api = Some(api);
+                vec![ReplayOutput::Subscribed]
+            }
+            CaptureEvent::Frame(text) => match &mut self.buffered {
+                Some(buffered) if self.api.is_some() => {
+                    buffered.push((text, time_us));
+                    Vec::new()
+                }
+                _ => self.handle_text(&text, time_us).into_iter().collect(),
+            },
+            CaptureEvent::Snapshot(snapshot) => {
+                let Some(api) = &mut self.api else {
+                    return Vec::new();
+                };
+                if let Err(e) = api.handle_snapshot(&snapshot) {
+                    return self.fail(e).into_iter().collect();
+                }
+                let mut outputs = Vec::new();
+                for (text, time_us) in self.buffered.take().unwrap_or_default() {
+                    outputs.extend(self.handle_text(&text, time_us));
+                }
+                outputs
+            }
+            CaptureEvent::Disconnected(_) => self.fail_silently(),
+        }
+    }
+}
+
+/// Feed which replays a capture instead of connecting to the exchange
+pub(in crate::feed) struct ReplayOrderbookFeed<ExchangeApi: OrderbookWsApi, T: BookCallback + Clone>
+{
+    handle: Option<JoinHandle<()>>,
+    // Cancels the task of `handle`
+    cancellation: CancellationToken,
+    api: ExchangeApi,
+    callback: T,
+    status: Arc<FeedStatusTracker>,
+    // Status entry of the feed, registered on the first start
+    status_handle: Option<FeedStatusHandle>,
+    replay: Arc<CaptureReplay>,
+    // Instrument of the last start, used by restart
+    instrument: Option<Instrument>,
+}
+
+impl<ExchangeApi: OrderbookWsApi, T: BookCallback + Clone> ReplayOrderbookFeed<ExchangeApi, T> {
+    pub fn new(
+        api: ExchangeApi,
+        callback: T,
+        status: Arc<FeedStatusTracker>,
+        replay: Arc<CaptureReplay>,
+    ) -> Self {
+        Self {
+            handle: None,
+            cancellation: CancellationToken::new(),
+            api,
+            callback,
+            status,
+            status_handle: None,
+            replay,
+            instrument: None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<ExchangeApi: OrderbookWsApi, T: BookCallback + Clone> OrderbookFeed
+    for ReplayOrderbookFeed<ExchangeApi, T>
+{
+    async fn start(&mut self, instrument: &Instrument) {
+        let exchange = ExchangeApi::exchange();
+        if self.handle.is_some() {
+            return;
+        }
+        let Some(symbol) = listed_symbol(&mut self.api, instrument) else {
+            return;
+        };
+        self.instrument = Some(instrument.clone());
+        let callback = self.callback.clone();
+        let mut connection = ReplayConnection::new(self.api.clone(), &symbol);
+        let status = self
+            .status_handle
+            .get_or_insert_with(|| self.status.register(exchange, &symbol))
+            .clone();
+        let mut records = self.replay.subscribe(exchange, &symbol);
+        self.cancellation = CancellationToken::new();
+        let cancellation = self.cancellation.clone();
+        let handle = tokio::spawn(async move {
+            status.set_state(FeedState::Connecting);
+            info!(target : "OrderbookFeed", "Replaying {exchange:?} feed of {symbol}");
+            loop {
+                let record = tokio::select! {
+                    _ = cancellation.cancelled() => break,
+                    record = records.recv() => match record {
+                        Some(record) => record,
+                        None => break,
+                    },
+                };
+                for output in connection.handle(record) {
+                    match output {
+                        ReplayOutput::Subscribed => status.set_state(FeedState::Subscribed),
+                        ReplayOutput::Book(book) => {
+                            status.book_received();
+                            callback.accept_book(book, exchange).await;
+                        }
+                        ReplayOutput::Disconnected => {
+                            callback.disconnected(exchange).await;
+                            status.set_state(FeedState::Connecting);
+                        }
+                    }
+                }
+            }
+            if connection.is_connected() {
+                callback.disconnected(exchange).await;
+            }
+            info!(target : "OrderbookFeed", "Finished replay of {exchange:?} feed of {symbol}");
+            status.set_state(FeedState::Stopped);
+        });
+        self.handle = Some(handle);
+    }
+
+    async fn stop(&mut self) {
+        self.cancellation.cancel();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.await {
+                error!(target : "OrderbookFeed", "{:?} replay task failed: {e}", ExchangeApi::exchange());
+            }
+        }
+    }
+
+    async fn restart(&mut self) {
+        self.stop().await;
+        if let Some(instrument) = self.instrument.clone() {
+            self.start(&instrument).await;
+        }
+    }
+}
+
+/// Reads the capture on a blocking thread, which stops once the receiver is dropped
+fn read_records(directory: PathBuf) -> std::io::Result<mpsc::Receiver<CaptureRecord>> {
+    // Records read ahead of the replay
+    const READ_AHEAD: usize = 1024;
+    let records = read_capture(&directory)?;
+    let (sender, receiver) = mpsc::channel(READ_AHEAD);
+    tokio::task::spawn_blocking(move || {
+        for record in records {
+            if sender.blocking_send(record).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::Exchange;
+    use crate::feed::exchanges::binance::BinanceOrderbookWsApi;
+    use crate::feed::recorder::{CaptureConfig, CaptureEvent, CaptureRecord, CaptureRecorder};
+    use crate::feed::replay::{
+        CaptureReplay, ReplayConfig, ReplayConnection, ReplayOutput, ReplaySpeed, ReplayStream,
+    };
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    const BOOK: &str = r#"{"stream":"btcusdt@depth5@100ms","data":{"lastUpdateId":1,
+        "bids":[["100.0","1.0"]],"asks":[["101.0","1.0"]]}}"#;
+
+    fn record(time_us: u64, event: CaptureEvent) -> CaptureRecord {
+        CaptureRecord {
+            time_us,
+            exchange: Exchange::Binance,
+            symbol: "btcusdt".to_string(),
+            event,
+        }
+    }
+
+    #[test]
+    fn captured_frames_produce_books() {
+        let mut connection = ReplayConnection::new(BinanceOrderbookWsApi::new(5), "btcusdt");
+        assert!(!connection.accepts(&CaptureRecord {
+            exchange: Exchange::Kraken,
+            ..record(0, CaptureEvent::Subscribed)
+        }));
+        let mut replay = |time_us, event| connection.handle(record(time_us, event));
+
+        // Frames before the confirmation belong to the subscription
+        assert!(replay(1, CaptureEvent::Subscribe("{}".to_string())).is_empty());
+        assert!(replay(2, CaptureEvent::Frame(BOOK.to_string())).is_empty());
+        assert!(matches!(
+            replay(3, CaptureEvent::Subscribed)[..],
+            [ReplayOutput::Subscribed]
+        ));
+        // Unparsable frames are skipped
+        assert!(replay(4, CaptureEvent::Frame("{".to_string())).is_empty());
+        let outputs = replay(5, CaptureEvent::Frame(BOOK.to_string()));
+        let [ReplayOutput::Book(book)] = &outputs[..] else {
+            panic!("expected a book, got {outputs:?}");
+        };
+        assert_eq!(book.bids().len(), 1);
+        assert!(matches!(
+            replay(6, CaptureEvent::Disconnected("timeout".to_string()))[..],
+            [ReplayOutput::Disconnected]
+        ));
+        assert!(replay(7, CaptureEvent::Frame(BOOK.to_string())).is_empty());
+    }
+
+    #[tokio::test]
+    async fn records_are_dispatched_in_capture_order() {
+        let directory = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let recorder = CaptureRecorder::start(CaptureConfig {
+            directory: directory.clone(),
+            ..CaptureConfig::default()
+        })
+        .unwrap();
+        let frame = |time_us, exchange, text: &str| CaptureRecord {
+            time_us,
+            exchange,
+            symbol: "btcusdt".to_string(),
+            event: CaptureEvent::Frame(text.to_string()),
+        };
+        for record in [
+            frame(1_000_000, Exchange::Binance, "1"),
+            // Written by another feed before the first record
+            frame(999_000, Exchange::Kraken, "0"),
+            frame(1_010_000, Exchange::Kraken, "2"),
+            frame(1_020_000, Exchange::Bitstamp, "3"),
+            frame(1_040_000, Exchange::Binance, "4"),
+        ] {
+            recorder.record(record);
+        }
+        recorder.close();
+
+        let replay = CaptureReplay::new(ReplayConfig {
+            directory: directory.clone(),
+            speed: ReplaySpeed::RealTime,
+        });
+        let mut binance = replay.subscribe(Exchange::Binance, "btcusdt");
+        let mut kraken = replay.subscribe(Exchange::Kraken, "btcusdt");
+        let start = Instant::now();
+        replay.start();
+        let text = |record: Option<CaptureRecord>| match record.unwrap().event {
+            CaptureEvent::Frame(text) => text,
+            event => panic!("unexpected {event:?}"),
+        };
+        assert_eq!(text(kraken.recv().await), "0");
+        assert_eq!(text(kraken.recv().await), "2");
+        // Both feeds are paced from the first record of the capture
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert_eq!(text(binance.recv().await), "1");
+        assert_eq!(text(binance.recv().await), "4");
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        // Records of streams without feed are skipped and the records end with the capture
+        assert!(binance.recv().await.is_none());
+        assert!(kraken.recv().await.is_none());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use crate::defines::error::WebsocketError;
+use crate::defines::grpc_scheme::{ExchangeFeedStatus, FeedState, FeedStatusReport};
+use crate::defines::Exchange;
+use async_broadcast::{InactiveReceiver, Receiver, Sender};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::Status;
+
+/// Connection state of all feeds. Reports are published on every state change and
+/// periodically so that the last book time of streaming feeds stays current
+#[derive(Debug)]
+pub(crate) struct FeedStatusTracker {
+    feeds: Mutex<Vec<ExchangeFeedStatus>>,
+    sender: Sender<Result<FeedStatusReport, Status>>,
+    receiver: InactiveReceiver<Result<FeedStatusReport, Status>>,
+}
+
+impl FeedStatusTracker {
+    // Number of reports a client can lag behind before old ones are dropped
+    const BUFFER_SIZE: usize = 16;
+    pub fn new() -> Arc<Self> {
+        let (mut sender, rx) = async_broadcast::broadcast(Self::BUFFER_SIZE);
+        sender.set_overflow(true);
+        Arc::new(Self {
+            feeds: Mutex::new(Vec::new()),
+            sender,
+            receiver: rx.deactivate(),
+        })
+    }
+    /// Adds a feed in state connecting, its status is updated through the returned handle
+    pub fn register(self: &Arc<Self>, exchange: Exchange, symbol: &str) -> FeedStatusHandle {
+        let index = {
+            let mut feeds = self.feeds.lock();
+            feeds.push(ExchangeFeedStatus {
+                exchange: exchange.name().to_string(),
+                symbol: symbol.to_string(),
+                state: FeedState::Connecting.into(),
+                last_book_time_ms: 0,
+                reconnect_count: 0,
+                last_error: String::new(),
+                retry_delay_ms: 0,
+            });
+            feeds.len() - 1
+        };
+        self.publish();
+        FeedStatusHandle {
+            tracker: self.clone(),
+            index,
+        }
+    }
+    pub fn report(&self) -> FeedStatusReport {
+        FeedStatusReport {
+            feeds: self.feeds.lock().clone(),
+        }
+    }
+    pub fn subscribe(&self) -> Receiver<Result<FeedStatusReport, Status>> {
+        self.receiver.activate_cloned()
+    }
+    /// Publishes a report every `interval` until the tracker is dropped
+    pub fn publish_every(self: &Arc<Self>, interval: Duration) {
+        let tracker = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match tracker.upgrade() {
+                    Some(tracker) => tracker.publish(),
+                    None => return,
+                }
+            }
+        });
+    }
+    /// Sends `status` as the final message and ends all report streams
+    pub fn close(&self, status: Status) {
+        let _ = self.sender.try_broadcast(Err(status));
+        self.sender.close();
+    }
+    fn publish(&self) {
+        // Error only means that no one is subscribed
+        let _ = self.sender.try_broadcast(Ok(self.report()));
+    }
+    fn update(&self, index: usize, update: impl FnOnce(&mut ExchangeFeedStatus) -> bool) {
+        let changed = update(&mut self.feeds.lock()[index]);
+        if changed {
+            self.publish();
+        }
+    }
+}
+
+/// Status entry of a single feed
+#[derive(Debug, Clone)]
+pub(crate) struct FeedStatusHandle {
+    tracker: Arc<FeedStatusTracker>,
+    index: usize,
+}
+
+impl FeedStatusHandle {
+    pub fn set_state(&self, state: FeedState) {
+        self.tracker.update(self.index, |status| {
+            let changed = status.state() != state;
+            status.set_state(state);
+            changed
+        });
+    }
+    /// Records the time of the latest book, the first one switches the feed to streaming
+    pub fn book_received(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.tracker.update(self.index, |status| {
+            status.last_book_time_ms = now;
+            let changed = status.state() != FeedState::Streaming;
+            status.set_state(FeedState::Streaming);
+            changed
+        });
+    }
+    /// Records the error which ended the last connection attempt
+    pub fn backing_off(&self, error: &WebsocketError, delay: Duration, circuit_open: bool) {
+        self.tracker.update(self.index, |status| {
+            status.set_state(if circuit_open {
+                FeedState::CircuitOpen
+            } else {
+                FeedState::BackingOff
+            });
+            status.reconnect_count += 1;
+            status.last_error = error.to_string();
+            status.retry_delay_ms = delay.as_millis() as u64;
+            true
+        });
+    }
+    /// Records the fatal error which stopped the feed
+    pub fn stopped(&self, error: &WebsocketError) {
+        self.tracker.update(self.index, |status| {
+            status.set_state(FeedState::Stopped);
+            status.last_error = error.to_string();
+            status.retry_delay_ms = 0;
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::error::WebsocketError;
+    use crate::defines::grpc_scheme::FeedState;
+    use crate::defines::Exchange;
+    use crate::feed::status::FeedStatusTracker;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn state_changes_are_published() {
+        let tracker = FeedStatusTracker::new();
+        let mut receiver = tracker.subscribe();
+        let handle = tracker.register(Exchange::Kraken, "BTC/USD");
+        handle.set_state(FeedState::Subscribed);
+        handle.book_received();
+        // Only the first book changes the state
+        handle.book_received();
+        handle.backing_off(
+            &WebsocketError::UnexpectedClosure,
+            Duration::from_secs(1),
+            false,
+        );
+
+        let states: Vec<FeedState> = (0..4)
+            .map(|_| receiver.try_recv().unwrap().unwrap().feeds[0].state())
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                FeedState::Connecting,
+                FeedState::Subscribed,
+                FeedState::Streaming,
+                FeedState::BackingOff
+            ]
+        );
+        assert!(receiver.try_recv().is_err());
+        let status = &tracker.report().feeds[0];
+        assert_eq!(status.reconnect_count, 1);
+        assert_eq!(status.last_error, "connection closed unexpectedly");
+        assert_ne!(status.last_book_time_ms, 0);
+        assert_eq!(status.retry_delay_ms, 1000);
+    }
+}
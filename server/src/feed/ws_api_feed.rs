@@ -1,40 +1,132 @@
 use crate::defines::error::{WebsocketError, WebsocketResult};
-use crate::defines::json_parser::JSONError;
+use crate::defines::instrument::{Instrument, Listing};
 use crate::defines::Exchange;
+use crate::feed::recorder::{CaptureEvent, StreamRecorder};
+use crate::helper::unix_time_us;
 use crate::marketdata::Orderbook;
 use futures_util::{SinkExt, StreamExt};
 use log::info;
-use std::marker::PhantomData;
+use std::collections::VecDeque;
 use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio::time::sleep;
+use tokio::time::{sleep, sleep_until, timeout, Instant};
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use url::Url;
 
-pub(in crate::feed) trait OrderbookWsApi: Send + Sync {
-    fn subscription_message(symbol: &str) -> String;
+/// Liveness checks of a connection
+#[derive(Debug, Copy, Clone)]
+pub(in crate::feed) struct KeepAlive {
+    /// Websocket pings and the exchange's heartbeat message are sent this often.
+    /// Without any message for twice this interval the connection is considered dead
+    pub ping_interval: Duration,
+    /// Longest time without a book update, the exchange may still answer pings.
+    /// `None` for books which legitimately stay unchanged for long
+    pub inactivity_timeout: Option<Duration>,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(15),
+            inactivity_timeout: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+/// Protocol of an exchange orderbook stream. Every connection works on a fresh clone of
+/// the configured instance, so adapters may keep local book state between messages
+pub(in crate::feed) trait OrderbookWsApi: Clone + Send + Sync + 'static {
+    /// Symbol of `instrument` in the exchange's wire format, used unless the listing
+    /// overrides it
+    fn native_symbol(instrument: &Instrument) -> String;
+    /// Lets adapters pick up instrument properties such as the tick size
+    fn configure(&mut self, _listing: &Listing) {}
+    fn subscription_message(&self, symbol: &str) -> String;
+    /// Sent before the connection is closed on purpose
+    fn unsubscription_message(&self, _symbol: &str) -> Option<String> {
+        None
+    }
     /// Returns true if `message` confirms orderbook subscription for `symbol`
     fn verify_confirmation(symbol: &str, message: &str) -> bool;
-    fn handle_message(msg: &str) -> Result<Orderbook, JSONError>;
+    /// Returns the exchange's reason if `message` rejects the subscription
+    fn subscription_error(_message: &str) -> Option<String> {
+        None
+    }
+    /// Returns `Ok(None)` for messages which do not produce a new book (e.g. heartbeats).
+    /// Errors other than `WebsocketError::JsonError` force a reconnect
+    fn handle_message(&mut self, msg: &str) -> WebsocketResult<Option<Orderbook>>;
+    /// REST endpoint of the initial book snapshot, for APIs which only stream diffs.
+    /// Messages received while the snapshot is loaded are buffered and handled afterwards
+    fn snapshot_endpoint(&self, _symbol: &str) -> Option<Url> {
+        None
+    }
+    fn handle_snapshot(&mut self, _msg: &str) -> WebsocketResult<()> {
+        Ok(())
+    }
     fn connection_endpoint() -> Url;
     fn exchange() -> Exchange;
+    fn keep_alive() -> KeepAlive {
+        KeepAlive::default()
+    }
+    /// Application level ping sent along with every websocket ping
+    fn heartbeat_message() -> Option<String> {
+        None
+    }
 }
 
 type WsStreamTT = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
 pub(in crate::feed) struct OrderbookWebsocket<ExchangeApi: OrderbookWsApi> {
     stream: WsStreamTT,
-    api: PhantomData<ExchangeApi>,
+    api: ExchangeApi,
+    symbol: String,
+    // Messages received while waiting for the book snapshot with their receive time
+    buffered: VecDeque<(String, u64)>,
+    keep_alive: KeepAlive,
+    last_message: Instant,
+    last_book: Instant,
+    next_ping: Instant,
+    recorder: Option<StreamRecorder>,
 }
 
 impl<ExchangeApi: OrderbookWsApi> OrderbookWebsocket<ExchangeApi> {
-    pub async fn connect_and_subscribe(symbol: &str) -> WebsocketResult<Self> {
+    /// Connects to the exchange, all traffic is captured by `recorder` if it is set
+    pub async fn connect_and_subscribe(
+        api: ExchangeApi,
+        symbol: &str,
+        keep_alive: KeepAlive,
+        recorder: Option<StreamRecorder>,
+    ) -> WebsocketResult<Self> {
         let endpoint = ExchangeApi::connection_endpoint();
+        Self::connect(api, endpoint, symbol, keep_alive, recorder).await
+    }
+    /// Same as `connect_and_subscribe` but connects to `endpoint` instead of the exchange
+    #[cfg(test)]
+    pub async fn connect_and_subscribe_to(
+        api: ExchangeApi,
+        endpoint: Url,
+        symbol: &str,
+    ) -> WebsocketResult<Self> {
+        Self::connect(api, endpoint, symbol, ExchangeApi::keep_alive(), None).await
+    }
+    async fn connect(
+        api: ExchangeApi,
+        endpoint: Url,
+        symbol: &str,
+        keep_alive: KeepAlive,
+        recorder: Option<StreamRecorder>,
+    ) -> WebsocketResult<Self> {
+        let record = |event| {
+            if let Some(recorder) = &recorder {
+                recorder.record(event);
+            }
+        };
         info!(target : "OrderbookFeed", "Connecting to {:?} at {endpoint}", ExchangeApi::exchange() );
         let (mut stream, _) = connect_async(endpoint).await?;
         info!(target : "OrderbookFeed", "Connected to {:?}", ExchangeApi::exchange() );
-        let subscription_message = ExchangeApi::subscription_message(symbol);
+        let subscription_message = api.subscription_message(symbol);
+        record(CaptureEvent::Subscribe(subscription_message.clone()));
         stream.send(Message::text(subscription_message)).await?;
         // First message should be subscription confirmation, but we'll allow 10 messages
         let mut sub_confirmation_received = false;
@@ -50,9 +142,13 @@ impl<ExchangeApi: OrderbookWsApi> OrderbookWebsocket<ExchangeApi> {
                 let message = message.unwrap()?;
                 match message {
                     Message::Text(txt_msg) => {
+                        record(CaptureEvent::Frame(txt_msg.clone()));
                         if ExchangeApi::verify_confirmation(symbol, &txt_msg) {
                           sub_confirmation_received = true; break;
                         }
+                        if let Some(reason) = ExchangeApi::subscription_error(&txt_msg) {
+                            return Err(WebsocketError::SubscriptionRejected(reason));
+                        }
                     }
                       // Tungstenite replies to pings by itself
                      Message::Ping(_) => {}
@@ -71,28 +167,107 @@ impl<ExchangeApi: OrderbookWsApi> OrderbookWebsocket<ExchangeApi> {
             Err(WebsocketError::NoConfirmationReceived)
         } else {
             info!(target : "OrderbookFeed", "Subscribed to {:?}", ExchangeApi::exchange() );
-            Ok(Self {
+            record(CaptureEvent::Subscribed);
+            let snapshot_endpoint = api.snapshot_endpoint(symbol);
+            let now = Instant::now();
+            let mut ws = Self {
                 stream,
-                api: Default::default(),
-            })
+                api,
+                symbol: symbol.to_string(),
+                buffered: VecDeque::new(),
+                keep_alive,
+                last_message: now,
+                last_book: now,
+                next_ping: now + keep_alive.ping_interval,
+                recorder,
+            };
+            if let Some(snapshot_endpoint) = snapshot_endpoint {
+                ws.synchronise(snapshot_endpoint).await?;
+            }
+            Ok(ws)
         }
     }
+    /// Loads the book snapshot while buffering all book messages received in the meantime
+    async fn synchronise(&mut self, snapshot_endpoint: Url) -> WebsocketResult<()> {
+        info!(target : "OrderbookFeed", "Requesting {:?} snapshot from {snapshot_endpoint}", ExchangeApi::exchange() );
+        let request = timeout(Duration::from_secs(15), fetch_snapshot(snapshot_endpoint));
+        tokio::pin!(request);
+        let snapshot = loop {
+            tokio::select! {
+                snapshot = &mut request => {
+                    break snapshot.map_err(|_| WebsocketError::Timeout)??;
+                },
+                message = self.stream.next() => {
+                    match message.ok_or(WebsocketError::UnexpectedClosure)?? {
+                        Message::Text(txt_msg) => {
+                            let received_at = unix_time_us();
+                            self.record(received_at, || CaptureEvent::Frame(txt_msg.clone()));
+                            self.buffered.push_back((txt_msg, received_at));
+                        }
+                        Message::Close(_) => return Err(WebsocketError::UnexpectedClosure),
+                        _ => {}
+                    }
+                }
+            }
+        };
+        self.record(unix_time_us(), || CaptureEvent::Snapshot(snapshot.clone()));
+        self.api.handle_snapshot(&snapshot)
+    }
+    /// Captures the event created by `event` if recording is enabled
+    fn record(&self, time_us: u64, event: impl FnOnce() -> CaptureEvent) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record_at(time_us, event());
+        }
+    }
+    #[cfg(test)]
+    pub fn api(&self) -> &ExchangeApi {
+        &self.api
+    }
+    #[cfg(test)]
+    pub fn set_keep_alive(&mut self, keep_alive: KeepAlive) {
+        self.keep_alive = keep_alive;
+        self.next_ping = Instant::now() + keep_alive.ping_interval;
+    }
     pub async fn next_book(&mut self) -> Result<Orderbook, WebsocketError> {
+        while let Some((txt_msg, received_at)) = self.buffered.pop_front() {
+            if let Some(book) = self.handle_text(&txt_msg, received_at)? {
+                self.last_book = Instant::now();
+                return Ok(book);
+            }
+        }
         loop {
-            let message = self.stream.next().await;
+            let silence_deadline = self.last_message + self.keep_alive.ping_interval * 2;
+            let book_deadline = self
+                .keep_alive
+                .inactivity_timeout
+                .map(|timeout| self.last_book + timeout);
+            let message = tokio::select! {
+                message = self.stream.next() => message,
+                _ = sleep_until(self.next_ping) => {
+                    self.ping().await?;
+                    continue;
+                },
+                _ = sleep_until(silence_deadline) => {
+                    return Err(WebsocketError::Inactive("no message received"));
+                },
+                _ = sleep_until(book_deadline.unwrap_or(silence_deadline)), if book_deadline.is_some() => {
+                    return Err(WebsocketError::Inactive("no book update received"));
+                },
+            };
+            self.last_message = Instant::now();
             if message.is_none() {
                 return Err(WebsocketError::UnexpectedClosure);
             }
             let inner_message = message.unwrap()?;
             match inner_message {
-                Message::Text(txt_msg) => match ExchangeApi::handle_message(&txt_msg) {
-                    Ok(book) => {
+                Message::Text(txt_msg) => {
+                    let received_at = unix_time_us();
+                    self.record(received_at, || CaptureEvent::Frame(txt_msg.clone()));
+                    if let Some(book) = self.handle_text(&txt_msg, received_at)? {
+                        self.last_book = self.last_message;
                         return Ok(book);
                     }
-                    Err(e) => {
-                        info!(target : "OrderbookFeed", "Unexpected JSON {txt_msg}, error: {e:?}")
-                    }
-                },
+                }
                 // Tungstenite replies to pings by itself
                 Message::Ping(_) => {}
                 Message::Pong(_) => {}
@@ -105,4 +280,50 @@ impl<ExchangeApi: OrderbookWsApi> OrderbookWebsocket<ExchangeApi> {
             }
         }
     }
+    /// Unsubscribes and closes the connection, waiting shortly for the exchange's close frame
+    pub async fn close(mut self) {
+        if let Some(message) = self.api.unsubscription_message(&self.symbol) {
+            let _ = self.stream.send(Message::text(message)).await;
+        }
+        if self.stream.close(None).await.is_err() {
+            return;
+        }
+        // Messages still in flight are dropped until the close frame is answered
+        let _ = timeout(Duration::from_secs(1), async {
+            while let Some(Ok(_)) = self.stream.next().await {}
+        })
+        .await;
+        info!(target : "OrderbookFeed", "Closed {:?} connection", ExchangeApi::exchange());
+    }
+    async fn ping(&mut self) -> WebsocketResult<()> {
+        self.next_ping = Instant::now() + self.keep_alive.ping_interval;
+        self.stream.send(Message::Ping(Vec::new())).await?;
+        if let Some(heartbeat) = ExchangeApi::heartbeat_message() {
+            self.stream.send(Message::text(heartbeat)).await?;
+        }
+        Ok(())
+    }
+    /// Unparsable messages are logged and skipped, all other errors are returned
+    fn handle_text(
+        &mut self,
+        txt_msg: &str,
+        received_at_us: u64,
+    ) -> WebsocketResult<Option<Orderbook>> {
+        match self.api.handle_message(txt_msg) {
+            Ok(Some(mut book)) => {
+                book.set_received_at(received_at_us);
+                Ok(Some(book))
+            }
+            Err(WebsocketError::JsonError(e)) => {
+                info!(target : "OrderbookFeed", "Unexpected JSON {txt_msg}, error: {e:?}");
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+}
+
+async fn fetch_snapshot(endpoint: Url) -> WebsocketResult<String> {
+    let response = reqwest::get(endpoint).await?.error_for_status()?;
+    Ok(response.text().await?)
 }
@@ -0,0 +1,539 @@
+use crate::defines::grpc_scheme::{ClientLag, ClientLagReport, Summary, SummaryDelta};
+use crate::grpc_server::delta::summary_delta;
+use crate::helper::unix_time_us;
+use crate::marketdata::{Subscription, ViewState};
+use futures_util::Stream;
+use halfbrown::HashMap;
+use log::info;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tonic::Status;
+
+pub(crate) type SummaryStream = Pin<Box<dyn Stream<Item = Result<Summary, Status>> + Send>>;
+pub(crate) type DeltaStream = Pin<Box<dyn Stream<Item = Result<SummaryDelta, Status>> + Send>>;
+
+/// When clients which cannot keep up with the summaries are disconnected. Slow clients
+/// always receive the latest summary, the ones published in between are skipped
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct LagPolicy {
+    /// Length of the windows over which skipped summaries are counted
+    pub window: Duration,
+    /// Fraction of the summaries of a window a client may skip without lagging
+    pub max_skipped_ratio: f64,
+    /// Consecutive lagging windows after which the client is disconnected, 0 never
+    /// disconnects
+    pub max_lagging_windows: u32,
+}
+
+impl Default for LagPolicy {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            max_skipped_ratio: 0.5,
+            max_lagging_windows: 3,
+        }
+    }
+}
+
+/// Lag of every connected summary stream
+#[derive(Debug, Default)]
+pub(crate) struct ClientRegistry {
+    clients: Mutex<HashMap<u64, ClientLag>>,
+    next_id: AtomicU64,
+}
+
+impl ClientRegistry {
+    pub fn report(&self) -> ClientLagReport {
+        let mut clients: Vec<ClientLag> = self.clients.lock().values().cloned().collect();
+        clients.sort_by_key(|client| client.client_id);
+        ClientLagReport { clients }
+    }
+    /// Stream of the summaries of `subscription` which tracks the lag of the client
+    pub fn summary_stream(
+        self: &Arc<Self>,
+        subscription: Subscription,
+        peer: String,
+        symbol: String,
+        policy: LagPolicy,
+    ) -> SummaryStream {
+        let (client, _) = self.register(subscription, peer, symbol, policy);
+        Box::pin(futures_util::stream::unfold(
+            client,
+            |mut client| async move {
+                let item = client.next().await?;
+                Some((item.map(|summary| Summary::clone(&summary)), client))
+            },
+        ))
+    }
+    /// Like `summary_stream`, but every summary is sent as delta to the previous one the
+    /// client received
+    pub fn delta_stream(
+        self: &Arc<Self>,
+        subscription: Subscription,
+        peer: String,
+        symbol: String,
+        policy: LagPolicy,
+    ) -> DeltaStream {
+        let (client, previous) = self.register(subscription, peer, symbol, policy);
+        Box::pin(futures_util::stream::unfold(
+            (client, previous),
+            |(mut client, mut previous): (ClientStream, Option<Arc<Summary>>)| async move {
+                let item = client.next().await?.map(|summary| {
+                    let delta = summary_delta(previous.as_deref(), &summary);
+                    previous = Some(summary);
+                    delta
+                });
+                Some((item, (client, previous)))
+            },
+        ))
+    }
+    /// Client stream of `subscription` and the summary a resuming client received last
+    fn register(
+        self: &Arc<Self>,
+        subscription: Subscription,
+        peer: String,
+        symbol: String,
+        policy: LagPolicy,
+    ) -> (ClientStream, Option<Arc<Summary>>) {
+        let client_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.clients.lock().insert(
+            client_id,
+            ClientLag {
+                client_id,
+                peer,
+                symbol,
+                ..ClientLag::default()
+            },
+        );
+        let mut missed = VecDeque::from(subscription.resumed);
+        let last = missed.pop_front();
+        let mut receiver = subscription.receiver;
+        // The receiver treats the current summary as seen, new clients start with it instead
+        // of waiting for the levels to change. Delta streams send it as snapshot
+        if last.is_none() {
+            missed.extend(receiver.borrow_and_update().summary.clone());
+        }
+        let tracker = Arc::new(Mutex::new(LagTracker::new(
+            policy,
+            latest_sequence(&receiver),
+        )));
+        let lagged = Arc::new(Notify::new());
+        let cancellation = CancellationToken::new();
+        tokio::spawn(self.clone().watch_lag(
+            client_id,
+            receiver.clone(),
+            tracker.clone(),
+            lagged.clone(),
+            cancellation.clone(),
+        ));
+        let client = ClientStream {
+            receiver,
+            missed,
+            registry: self.clone(),
+            client_id,
+            tracker,
+            lagged,
+            last_sequence: last.as_ref().map(|summary| summary.sequence),
+            finished: false,
+            _watchdog: cancellation.drop_guard(),
+        };
+        (client, last)
+    }
+    /// Ends a lag window of the client every window length, independent of whether it reads
+    /// its stream. A client which stopped reading skips all summaries and is disconnected
+    /// once it lagged for too long
+    async fn watch_lag(
+        self: Arc<Self>,
+        client_id: u64,
+        receiver: watch::Receiver<ViewState>,
+        tracker: Arc<Mutex<LagTracker>>,
+        lagged: Arc<Notify>,
+        cancellation: CancellationToken,
+    ) {
+        let window = tracker.lock().policy.window;
+        let mut windows = interval_at(Instant::now() + window, window);
+        windows.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = cancellation.cancelled() => return,
+                _ = windows.tick() => {},
+            }
+            let mut tracker = tracker.lock();
+            let lagging_windows = tracker.end_window(latest_sequence(&receiver));
+            if let Some(lag) = self.clients.lock().get_mut(&client_id) {
+                lag.lagging_windows = lagging_windows;
+            }
+            if tracker.exceeded() {
+                lagged.notify_one();
+                return;
+            }
+        }
+    }
+}
+
+/// Sequence of the latest summary of the view, 0 before the first one
+fn latest_sequence(receiver: &watch::Receiver<ViewState>) -> u64 {
+    receiver
+        .borrow()
+        .summary
+        .as_ref()
+        .map_or(0, |summary| summary.sequence)
+}
+
+struct ClientStream {
+    receiver: watch::Receiver<ViewState>,
+    // Summaries a resuming client missed, sent before the live ones
+    missed: VecDeque<Arc<Summary>>,
+    registry: Arc<ClientRegistry>,
+    client_id: u64,
+    // Shared with the lag watchdog of the client
+    tracker: Arc<Mutex<LagTracker>>,
+    // Notified by the watchdog once the client lagged for too long
+    lagged: Arc<Notify>,
+    // Sequence of the last summary sent to the client
+    last_sequence: Option<u64>,
+    finished: bool,
+    // Stops the watchdog once the stream is dropped
+    _watchdog: DropGuard,
+}
+
+impl ClientStream {
+    async fn next(&mut self) -> Option<Result<Arc<Summary>, Status>> {
+        if self.finished {
+            return None;
+        }
+        if self.tracker.lock().exceeded() {
+            return Some(Err(self.disconnect()));
+        }
+        let summary = match self.missed.pop_front() {
+            Some(summary) => summary,
+            None => {
+                tokio::select! {
+                    changed = self.receiver.changed() => changed.ok()?,
+                    _ = self.lagged.notified() => return Some(Err(self.disconnect())),
+                }
+                let state = self.receiver.borrow_and_update().clone();
+                if let Some(status) = state.closed {
+                    self.finished = true;
+                    return Some(Err(status));
+                }
+                state.summary?
+            }
+        };
+        let skipped = self
+            .last_sequence
+            .map_or(0, |last| summary.sequence.saturating_sub(last + 1));
+        self.last_sequence = Some(summary.sequence);
+        self.tracker.lock().delivered += 1;
+        if let Some(lag) = self.registry.clients.lock().get_mut(&self.client_id) {
+            lag.delivered += 1;
+            lag.skipped += skipped;
+            lag.last_latency_us = unix_time_us().saturating_sub(summary.publish_timestamp_us);
+        }
+        Some(Ok(summary))
+    }
+    /// Ends the stream of a client which lagged for too long
+    fn disconnect(&mut self) -> Status {
+        self.finished = true;
+        let tracker = self.tracker.lock();
+        Status::resource_exhausted(format!(
+            "client skipped more than {:.0}% of the summaries in {} consecutive windows of {:?}",
+            tracker.policy.max_skipped_ratio * 100.,
+            tracker.lagging_windows,
+            tracker.policy.window
+        ))
+    }
+}
+
+impl Drop for ClientStream {
+    fn drop(&mut self) {
+        if let Some(lag) = self.registry.clients.lock().remove(&self.client_id) {
+            info!(target : "BookSummaryService", "Client {} ({}) disconnected after {} summaries, {} skipped", lag.client_id, lag.peer, lag.delivered, lag.skipped);
+        }
+    }
+}
+
+/// Counts skipped summaries per window
+#[derive(Debug)]
+struct LagTracker {
+    policy: LagPolicy,
+    // Sequence of the latest summary of the view when the window started
+    window_sequence: u64,
+    // Summaries delivered to the client in the window
+    delivered: u64,
+    lagging_windows: u32,
+}
+
+impl LagTracker {
+    fn new(policy: LagPolicy, latest_sequence: u64) -> Self {
+        Self {
+            policy,
+            window_sequence: latest_sequence,
+            delivered: 0,
+            lagging_windows: 0,
+        }
+    }
+    /// Ends the window, `latest_sequence` is the sequence of the view's latest summary.
+    /// Returns the number of consecutive lagging windows
+    fn end_window(&mut self, latest_sequence: u64) -> u32 {
+        let published = latest_sequence.saturating_sub(self.window_sequence);
+        let skipped = published.saturating_sub(self.delivered);
+        let ratio = skipped as f64 / published.max(1) as f64;
+        if ratio > self.policy.max_skipped_ratio {
+            self.lagging_windows += 1;
+        } else {
+            self.lagging_windows = 0;
+        }
+        self.window_sequence = latest_sequence;
+        self.delivered = 0;
+        self.lagging_windows
+    }
+    fn exceeded(&self) -> bool {
+        self.policy.max_lagging_windows > 0
+            && self.lagging_windows >= self.policy.max_lagging_windows
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::grpc_scheme::{Level, Summary};
+    use crate::grpc_server::clients::{ClientRegistry, LagPolicy, LagTracker};
+    use crate::marketdata::{
+        FullSummary, PricedLevel, ResumePoint, ResumeWindow, Subscription, SummaryView,
+        SummaryViews, ViewState,
+    };
+    use futures_util::StreamExt;
+    use rust_decimal::Decimal;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    fn publish(sender: &watch::Sender<ViewState>, sequence: u64) {
+        sender.send_modify(|state| {
+            state.summary = Some(Arc::new(Summary {
+                sequence,
+                ..Summary::default()
+            }))
+        });
+    }
+
+    #[test]
+    fn chronic_lag_exceeds_policy() {
+        let policy = LagPolicy {
+            window: Duration::from_secs(1),
+            max_skipped_ratio: 0.5,
+            max_lagging_windows: 2,
+        };
+        let mut tracker = LagTracker::new(policy, 10);
+        // Skips 3 of every 4 summaries
+        for window in 1..=2 {
+            tracker.delivered += 1;
+            assert_eq!(tracker.end_window(10 + 4 * window), window as u32);
+        }
+        assert!(tracker.exceeded());
+        // A window without skipped summaries resets the count
+        tracker.delivered += 2;
+        assert_eq!(tracker.end_window(20), 0);
+        // Windows without summaries do not lag
+        assert_eq!(tracker.end_window(20), 0);
+    }
+
+    #[tokio::test]
+    async fn slow_client_gets_latest_summary() {
+        let registry = Arc::new(ClientRegistry::default());
+        let (sender, receiver) = watch::channel(ViewState::default());
+        let mut stream = registry.summary_stream(
+            Subscription {
+                receiver,
+                resumed: Vec::new(),
+            },
+            "peer".to_string(),
+            "BTC/USDT".to_string(),
+            LagPolicy::default(),
+        );
+        publish(&sender, 1);
+        assert_eq!(stream.next().await.unwrap().unwrap().sequence, 1);
+        for sequence in 2..=5 {
+            publish(&sender, sequence);
+        }
+        assert_eq!(stream.next().await.unwrap().unwrap().sequence, 5);
+
+        let report = registry.report();
+        assert_eq!(report.clients.len(), 1);
+        assert_eq!(
+            (report.clients[0].delivered, report.clients[0].skipped),
+            (2, 3)
+        );
+
+        sender.send_modify(|state| state.closed = Some(tonic::Status::unavailable("closed")));
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        assert!(registry.report().clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_which_stopped_reading_is_disconnected() {
+        let registry = Arc::new(ClientRegistry::default());
+        let (sender, receiver) = watch::channel(ViewState::default());
+        let mut stream = registry.summary_stream(
+            Subscription {
+                receiver,
+                resumed: Vec::new(),
+            },
+            "peer".to_string(),
+            "BTC/USDT".to_string(),
+            LagPolicy {
+                window: Duration::from_millis(20),
+                max_skipped_ratio: 0.5,
+                max_lagging_windows: 2,
+            },
+        );
+        // The stream is not polled while summaries are published
+        for sequence in 1..=20 {
+            publish(&sender, sequence);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(registry.report().clients[0].lagging_windows, 2);
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn resumed_streams_continue_after_the_last_summary() {
+        let views = SummaryViews::with_resume_window(
+            ResumeWindow {
+                capacity: 16,
+                idle_retention: Duration::from_secs(60),
+                max_idle_views: 16,
+            },
+            7,
+        );
+        let best_bid = |price: u32| {
+            let bid = PricedLevel {
+                price: Decimal::from(price),
+                level: Level {
+                    exchange: "kraken".to_string(),
+                    price: price as f64,
+                    price_exact: price.to_string(),
+                    ..Level::default()
+                },
+            };
+            FullSummary {
+                bids: vec![bid],
+                asks: Vec::new(),
+            }
+        };
+        let live = views.subscribe(SummaryView::all(1), None).unwrap();
+        for price in 1..=3 {
+            views.publish(&best_bid(price));
+        }
+        let registry = Arc::new(ClientRegistry::default());
+        let resume = || {
+            views
+                .subscribe(
+                    SummaryView::all(1),
+                    Some(ResumePoint {
+                        epoch: 7,
+                        sequence: 1,
+                    }),
+                )
+                .unwrap()
+        };
+        let mut summaries = registry.summary_stream(
+            resume(),
+            "peer".to_string(),
+            "BTC/USDT".to_string(),
+            LagPolicy::default(),
+        );
+        let mut deltas = registry.delta_stream(
+            resume(),
+            "peer".to_string(),
+            "BTC/USDT".to_string(),
+            LagPolicy::default(),
+        );
+        drop(live);
+
+        // Missed summaries come first, then the live ones
+        views.publish(&best_bid(4));
+        let mut sequences = Vec::new();
+        for _ in 0..3 {
+            let summary = summaries.next().await.unwrap().unwrap();
+            assert_eq!(summary.epoch, 7);
+            sequences.push(summary.sequence);
+        }
+        assert_eq!(sequences, vec![2, 3, 4]);
+
+        // Deltas apply to the summary the client received last
+        let delta = deltas.next().await.unwrap().unwrap();
+        assert_eq!(
+            (delta.snapshot, delta.base_sequence, delta.sequence),
+            (false, 1, 2)
+        );
+        // The bid of summary 1 is replaced
+        assert_eq!(delta.bids.len(), 2);
+        for sequence in 3..=4 {
+            let delta = deltas.next().await.unwrap().unwrap();
+            assert_eq!(
+                (delta.base_sequence, delta.sequence),
+                (sequence - 1, sequence)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn new_clients_start_with_the_current_summary() {
+        let views = SummaryViews::new();
+        let first = views.subscribe(SummaryView::all(1), None).unwrap();
+        views.publish(&FullSummary {
+            bids: vec![PricedLevel {
+                price: Decimal::from(100),
+                level: Level {
+                    exchange: "kraken".to_string(),
+                    price: 100.,
+                    price_exact: "100".to_string(),
+                    ..Level::default()
+                },
+            }],
+            asks: Vec::new(),
+        });
+        let registry = Arc::new(ClientRegistry::default());
+        let subscribe = || views.subscribe(SummaryView::all(1), None).unwrap();
+        let mut summaries = registry.summary_stream(
+            subscribe(),
+            "peer".to_string(),
+            "BTC/USDT".to_string(),
+            LagPolicy::default(),
+        );
+        let mut deltas = registry.delta_stream(
+            subscribe(),
+            "peer".to_string(),
+            "BTC/USDT".to_string(),
+            LagPolicy::default(),
+        );
+        // The levels do not change again
+        let timeout = Duration::from_secs(1);
+        let summary = tokio::time::timeout(timeout, summaries.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!((summary.sequence, summary.bids.len()), (1, 1));
+        let delta = tokio::time::timeout(timeout, deltas.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(delta.snapshot);
+        assert_eq!((delta.sequence, delta.bids.len()), (1, 1));
+        drop(first);
+    }
+}
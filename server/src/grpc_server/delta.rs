@@ -0,0 +1,267 @@
+use crate::defines::grpc_scheme::{
+    CompactLevel, DeltaAction, ExchangeId, ExchangeTimestamps, Level, LevelDelta, Summary,
+    SummaryDelta,
+};
+use crate::defines::Exchange;
+use std::cmp::Ordering;
+
+/// Delta which turns `previous` into `next`, a snapshot if there is no previous summary
+pub(crate) fn summary_delta(previous: Option<&Summary>, next: &Summary) -> SummaryDelta {
+    let (base_sequence, bids, asks) = match previous {
+        Some(previous) => (
+            previous.sequence,
+            level_deltas(&previous.bids, &next.bids, |a, b| b.total_cmp(&a)),
+            level_deltas(&previous.asks, &next.asks, |a, b| a.total_cmp(&b)),
+        ),
+        None => (0, inserts(&next.bids), inserts(&next.asks)),
+    };
+    SummaryDelta {
+        sequence: next.sequence,
+        base_sequence,
+        snapshot: previous.is_none(),
+        publish_timestamp_us: next.publish_timestamp_us,
+        spread: next.spread,
+        spread_exact: next.spread_exact.clone(),
+        bids,
+        asks,
+        timestamps: exchange_timestamps(next),
+        epoch: next.epoch,
+    }
+}
+
+/// Timestamps of the exchanges in `summary`, all levels of an exchange come from the same
+/// book and share them
+fn exchange_timestamps(summary: &Summary) -> Vec<ExchangeTimestamps> {
+    let mut timestamps: Vec<ExchangeTimestamps> = Vec::new();
+    for level in summary.bids.iter().chain(summary.asks.iter()) {
+        let exchange = exchange_id(level) as i32;
+        if timestamps.iter().all(|known| known.exchange != exchange) {
+            timestamps.push(ExchangeTimestamps {
+                exchange,
+                exchange_timestamp_us: level.exchange_timestamp_us,
+                receive_timestamp_us: level.receive_timestamp_us,
+            });
+        }
+    }
+    timestamps
+}
+
+fn inserts(levels: &[Level]) -> Vec<LevelDelta> {
+    levels
+        .iter()
+        .enumerate()
+        .map(|(index, level)| delta(DeltaAction::Insert, index, Some(level)))
+        .collect()
+}
+
+/// Merges both sides in price order, levels are identified by exchange and price.
+/// `price_order` orders the prices of the side, best first
+fn level_deltas(
+    previous: &[Level],
+    next: &[Level],
+    price_order: impl Fn(f64, f64) -> Ordering,
+) -> Vec<LevelDelta> {
+    let order = |a: &Level, b: &Level| {
+        if a.exchange == b.exchange && a.price_exact == b.price_exact {
+            return Ordering::Equal;
+        }
+        price_order(a.price, b.price).then_with(|| a.exchange.cmp(&b.exchange))
+    };
+    let mut deltas = Vec::new();
+    let (mut old, mut new, mut index) = (0, 0, 0);
+    while old < previous.len() || new < next.len() {
+        let ordering = match (previous.get(old), next.get(new)) {
+            (Some(a), Some(b)) => order(a, b),
+            (Some(_), None) => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+        match ordering {
+            Ordering::Equal => {
+                if !same_level(&previous[old], &next[new]) {
+                    deltas.push(delta(DeltaAction::Update, index, Some(&next[new])));
+                }
+                old += 1;
+                new += 1;
+                index += 1;
+            }
+            Ordering::Less => {
+                deltas.push(delta(DeltaAction::Delete, index, None));
+                old += 1;
+            }
+            Ordering::Greater => {
+                deltas.push(delta(DeltaAction::Insert, index, Some(&next[new])));
+                new += 1;
+                index += 1;
+            }
+        }
+    }
+    deltas
+}
+
+/// Whether the level at the same exchange and price is unchanged, timestamps are sent
+/// separately
+fn same_level(a: &Level, b: &Level) -> bool {
+    a.amount_exact == b.amount_exact
+        && a.conversion_rate_exact == b.conversion_rate_exact
+        && a.stale == b.stale
+}
+
+fn delta(action: DeltaAction, index: usize, level: Option<&Level>) -> LevelDelta {
+    LevelDelta {
+        action: action as i32,
+        index: index as u32,
+        level: level.map(compact_level),
+    }
+}
+
+fn exchange_id(level: &Level) -> ExchangeId {
+    Exchange::from_name(&level.exchange)
+        .map(|exchange| exchange.id())
+        .unwrap_or(ExchangeId::UnknownExchange)
+}
+
+fn compact_level(level: &Level) -> CompactLevel {
+    CompactLevel {
+        exchange: exchange_id(level) as i32,
+        price: level.price,
+        amount: level.amount,
+        converted: level.converted,
+        conversion_rate: level.conversion_rate,
+        stale: level.stale,
+        price_exact: level.price_exact.clone(),
+        amount_exact: level.amount_exact.clone(),
+        conversion_rate_exact: level.conversion_rate_exact.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::grpc_scheme::{DeltaAction, ExchangeId, Level, LevelDelta, Summary};
+    use crate::grpc_server::delta::summary_delta;
+
+    fn level(exchange: &str, price: f64, amount: f64) -> Level {
+        Level {
+            exchange: exchange.to_string(),
+            price,
+            amount,
+            price_exact: price.to_string(),
+            amount_exact: amount.to_string(),
+            ..Level::default()
+        }
+    }
+
+    #[test]
+    fn deltas_of_changed_levels() {
+        let previous = Summary {
+            sequence: 4,
+            bids: vec![
+                level("binance", 100., 1.),
+                level("kraken", 99., 1.),
+                level("binance", 98., 1.),
+            ],
+            asks: vec![level("kraken", 101., 1.), level("binance", 102., 1.)],
+            ..Summary::default()
+        };
+        let snapshot = summary_delta(None, &previous);
+        assert!(snapshot.snapshot);
+        assert_eq!(snapshot.base_sequence, 0);
+        assert_eq!(snapshot.bids.len(), 3);
+        assert_eq!(
+            snapshot.asks[0].level.as_ref().unwrap().exchange,
+            ExchangeId::Kraken as i32
+        );
+
+        let next = Summary {
+            sequence: 6,
+            bids: vec![
+                level("binance", 100., 2.),
+                level("kraken", 99.5, 1.),
+                level("binance", 98., 1.),
+            ],
+            asks: vec![level("binance", 102., 1.), level("kraken", 103., 1.)],
+            ..Summary::default()
+        };
+        let delta = summary_delta(Some(&previous), &next);
+        assert!(!delta.snapshot);
+        assert_eq!((delta.base_sequence, delta.sequence), (4, 6));
+        let actions = |deltas: &[LevelDelta]| -> Vec<(i32, u32)> {
+            deltas
+                .iter()
+                .map(|delta| (delta.action, delta.index))
+                .collect()
+        };
+        assert_eq!(
+            actions(&delta.bids),
+            vec![
+                (DeltaAction::Update as i32, 0),
+                (DeltaAction::Insert as i32, 1),
+                (DeltaAction::Delete as i32, 2)
+            ]
+        );
+        assert_eq!(
+            actions(&delta.asks),
+            vec![
+                (DeltaAction::Delete as i32, 0),
+                (DeltaAction::Insert as i32, 1)
+            ]
+        );
+        assert!(delta.asks[0].level.is_none());
+
+        assert!(summary_delta(Some(&next), &next).bids.is_empty());
+    }
+
+    #[test]
+    fn only_changed_levels_are_sent() {
+        let with_times = |mut level: Level, receive_timestamp_us| {
+            level.exchange_timestamp_us = receive_timestamp_us - 10;
+            level.receive_timestamp_us = receive_timestamp_us;
+            level
+        };
+        let previous = Summary {
+            sequence: 1,
+            bids: vec![
+                with_times(level("binance", 100., 1.), 1_000),
+                with_times(level("kraken", 99., 1.), 1_000),
+            ],
+            asks: vec![with_times(level("kraken", 101., 1.), 1_000)],
+            ..Summary::default()
+        };
+        // New books of both exchanges, only the amount of the kraken bid changed
+        let next = Summary {
+            sequence: 2,
+            bids: vec![
+                with_times(level("binance", 100., 1.), 2_000),
+                with_times(level("kraken", 99., 2.), 3_000),
+            ],
+            asks: vec![with_times(level("kraken", 101., 1.), 3_000)],
+            ..Summary::default()
+        };
+        let delta = summary_delta(Some(&previous), &next);
+        assert_eq!(delta.bids.len(), 1);
+        assert_eq!(
+            (delta.bids[0].action, delta.bids[0].index),
+            (DeltaAction::Update as i32, 1)
+        );
+        assert_eq!(delta.bids[0].level.as_ref().unwrap().amount, 2.);
+        assert!(delta.asks.is_empty());
+
+        let timestamps: Vec<(i32, u64, u64)> = delta
+            .timestamps
+            .iter()
+            .map(|times| {
+                (
+                    times.exchange,
+                    times.exchange_timestamp_us,
+                    times.receive_timestamp_us,
+                )
+            })
+            .collect();
+        assert_eq!(
+            timestamps,
+            vec![
+                (ExchangeId::Binance as i32, 1_990, 2_000),
+                (ExchangeId::Kraken as i32, 2_990, 3_000)
+            ]
+        );
+    }
+}
@@ -1,44 +1,500 @@
+use crate::archive::SummaryArchive;
 use crate::defines::grpc_scheme::orderbook_aggregator_server::OrderbookAggregator;
-use crate::defines::grpc_scheme::{Empty, Summary};
+use crate::defines::grpc_scheme::{
+    ClientLagReport, ClientLagRequest, ExchangeBook, ExchangeBookRequest, FeedStatusReport,
+    FeedStatusRequest, Summary, SummaryQuery, SummaryRequest,
+};
+use crate::defines::instrument::{InstrumentRegistry, Market};
 use crate::defines::Exchange;
-use crate::feed::OrderbookFeedFactory;
-use crate::marketdata::BookAggregatorCallback;
-use async_broadcast::{InactiveReceiver, Receiver};
+use crate::feed::{
+    CaptureRecorder, FeedConfig, FeedStatusTracker, OrderbookFeed, OrderbookFeedFactory,
+};
+use crate::helper::unix_time_us;
+use crate::marketdata::{
+    BookAggregatorCallback, ConversionRateCallback, FullSummary, ResumePoint, ResumeWindow,
+    Subscription, SummaryView, SummaryViews,
+};
+use async_broadcast::Receiver;
+use clients::{ClientRegistry, DeltaStream, SummaryStream};
+use halfbrown::HashMap;
+use log::error;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+mod clients;
+mod delta;
+
+pub(crate) use clients::LagPolicy;
+
+/// How the summaries of every market are computed and published
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct SummaryConfig {
+    /// Levels of exchanges without a book update for this long are flagged as stale
+    pub stale_after: Option<Duration>,
+    /// Minimum time between two summaries of a market, books received in between are
+    /// coalesced into the next summary
+    pub min_publish_interval: Duration,
+    pub resume_window: ResumeWindow,
+}
 
-#[derive(Debug)]
 pub(crate) struct BookSummaryService {
-    receiver: InactiveReceiver<Result<Summary, tonic::Status>>,
+    // Aggregator of every market, keyed by market name
+    markets: HashMap<String, Arc<BookAggregatorCallback>>,
+    registry: InstrumentRegistry,
+    // Depth of the books of all feeds, requests cannot exceed it
+    max_depth: usize,
+    clients: Arc<ClientRegistry>,
+    lag_policy: LagPolicy,
+    status: Arc<FeedStatusTracker>,
+    // Archive of the published summaries if enabled
+    archive: Option<Arc<SummaryArchive>>,
+    lifecycle: LifecycleHandle,
+}
+
+/// Controls the feeds and client streams after the service was moved into the server
+#[derive(Clone)]
+pub(crate) struct LifecycleHandle {
+    feeds: Arc<tokio::sync::Mutex<Vec<Box<dyn OrderbookFeed>>>>,
+    summary_views: Vec<Arc<SummaryViews>>,
+    status: Arc<FeedStatusTracker>,
+    recorder: Option<Arc<CaptureRecorder>>,
+    archive: Option<Arc<SummaryArchive>>,
+}
+
+impl LifecycleHandle {
+    /// Reconnects all feeds, e.g. after network changes
+    pub async fn restart_feeds(&self) {
+        let mut feeds = self.feeds.lock().await;
+        futures_util::future::join_all(feeds.iter_mut().map(|feed| feed.restart())).await;
+    }
+    /// Unsubscribes all feeds, then sends a final status to every client and closes
+    /// their streams
+    pub async fn shutdown(&self) {
+        let mut feeds = self.feeds.lock().await;
+        futures_util::future::join_all(feeds.iter_mut().map(|feed| feed.stop())).await;
+        if let Some(recorder) = &self.recorder {
+            recorder.close();
+        }
+        if let Some(archive) = &self.archive {
+            archive.close();
+        }
+        for views in &self.summary_views {
+            views.close(Self::final_status());
+        }
+        self.status.close(Self::final_status());
+    }
+    fn final_status() -> Status {
+        Status::unavailable("server is shutting down")
+    }
 }
 
 impl BookSummaryService {
-    // Number of updates a client can lag behind before server will start dropping
-    // old messages
-    const BUFFER_SIZE: usize = 50;
-    pub fn new(symbol: &str) -> Self {
-        let (mut sender, rx) = async_broadcast::broadcast(Self::BUFFER_SIZE);
-        sender.set_overflow(true);
-        let receiver = rx.deactivate();
-        let callback = Arc::new(BookAggregatorCallback::new(sender.clone()));
-        let mut binance_feed =
-            OrderbookFeedFactory::create_feed(Exchange::Binance, callback.clone());
-        let mut bitstamp_feed = OrderbookFeedFactory::create_feed(Exchange::Bitstamp, callback);
-        let symbol_clone1 = symbol.to_string();
-        let symbol_clone2 = symbol.to_string();
-        tokio::spawn(async move { bitstamp_feed.start(&symbol_clone1).await });
-        tokio::spawn(async move { binance_feed.start(&symbol_clone2).await });
-
-        Self { receiver }
+    // Feed status is published at least this often
+    const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+    pub async fn new(
+        markets: &[Market],
+        registry: InstrumentRegistry,
+        feed_config: &FeedConfig,
+        summary_config: SummaryConfig,
+        lag_policy: LagPolicy,
+        archive: Option<Arc<SummaryArchive>>,
+    ) -> Self {
+        let status = FeedStatusTracker::new();
+        status.publish_every(Self::STATUS_INTERVAL);
+        let mut summary_markets = HashMap::new();
+        let mut summary_views = Vec::new();
+        let mut feeds = Vec::new();
+        // Start time of the instance, summaries of an earlier instance have a smaller epoch
+        let epoch = unix_time_us();
+        for market in markets {
+            let aggregator = Self::start_aggregation(
+                market,
+                feed_config,
+                summary_config,
+                epoch,
+                archive.as_ref(),
+                status.clone(),
+                &mut feeds,
+            )
+            .await;
+            summary_views.push(aggregator.views().clone());
+            summary_markets.insert(market.name.clone(), aggregator);
+        }
+        // All replayed feeds subscribed, they share the single pass over the capture
+        if let Some(replay) = &feed_config.replay {
+            replay.start();
+        }
+        let lifecycle = LifecycleHandle {
+            feeds: Arc::new(tokio::sync::Mutex::new(feeds)),
+            summary_views,
+            status: status.clone(),
+            recorder: feed_config.recorder.clone(),
+            archive: archive.clone(),
+        };
+        Self {
+            markets: summary_markets,
+            registry,
+            max_depth: feed_config.depth,
+            clients: Arc::new(ClientRegistry::default()),
+            lag_policy,
+            status,
+            archive,
+            lifecycle,
+        }
+    }
+
+    /// Service of `markets` without feeds, books are passed to the aggregators directly
+    #[cfg(test)]
+    fn without_feeds(markets: &[Market], registry: InstrumentRegistry, max_depth: usize) -> Self {
+        let status = FeedStatusTracker::new();
+        let markets: HashMap<_, _> = markets
+            .iter()
+            .map(|market| {
+                let views = Arc::new(SummaryViews::new());
+                let callback = BookAggregatorCallback::new(views, market, None);
+                (market.name.clone(), Arc::new(callback))
+            })
+            .collect();
+        let lifecycle = LifecycleHandle {
+            feeds: Arc::new(tokio::sync::Mutex::new(Vec::new())),
+            summary_views: markets
+                .values()
+                .map(|callback| callback.views().clone())
+                .collect(),
+            status: status.clone(),
+            recorder: None,
+            archive: None,
+        };
+        Self {
+            markets,
+            registry,
+            max_depth,
+            clients: Arc::new(ClientRegistry::default()),
+            lag_policy: LagPolicy::default(),
+            status,
+            archive: None,
+            lifecycle,
+        }
     }
+
+    pub fn lifecycle_handle(&self) -> LifecycleHandle {
+        self.lifecycle.clone()
+    }
+
+    /// Starts the feeds of all sources and conversions of `market` with their own aggregator
+    async fn start_aggregation(
+        market: &Market,
+        feed_config: &FeedConfig,
+        config: SummaryConfig,
+        epoch: u64,
+        archive: Option<&Arc<SummaryArchive>>,
+        status: Arc<FeedStatusTracker>,
+        feeds: &mut Vec<Box<dyn OrderbookFeed>>,
+    ) -> Arc<BookAggregatorCallback> {
+        let views = SummaryViews::with_resume_window(config.resume_window, epoch);
+        let mut callback = BookAggregatorCallback::new(Arc::new(views), market, config.stale_after);
+        if let Some(archive) = archive {
+            callback = callback.with_archive(archive.market(&market.name));
+        }
+        let callback = Arc::new(callback);
+        callback.limit_publish_rate(config.min_publish_interval);
+        callback.watch_staleness();
+        callback.watch_archive();
+        for (exchange, instrument) in &market.sources {
+            let mut feed = OrderbookFeedFactory::create_feed(
+                *exchange,
+                feed_config,
+                callback.clone(),
+                status.clone(),
+            );
+            feed.start(instrument).await;
+            feeds.push(feed);
+        }
+        // Conversion rates may not change for long, which is no reason to reconnect
+        let conversion_config = FeedConfig {
+            book_timeout: false,
+            ..feed_config.clone()
+        };
+        for conversion in &market.conversions {
+            let rate_callback = ConversionRateCallback::new(callback.clone(), conversion.clone());
+            let mut feed = OrderbookFeedFactory::create_feed(
+                conversion.exchange,
+                &conversion_config,
+                rate_callback,
+                status.clone(),
+            );
+            feed.start(&conversion.instrument).await;
+            feeds.push(feed);
+        }
+        callback
+    }
+
+    // tonic::Status is large but it is returned to the gRPC handlers as is
+    #[allow(clippy::result_large_err)]
+    fn aggregator(&self, symbol: &str) -> Result<&Arc<BookAggregatorCallback>, Status> {
+        if symbol.is_empty() {
+            if self.markets.len() == 1 {
+                return Ok(self.markets.values().next().unwrap());
+            }
+            return Err(Status::invalid_argument(
+                "symbol is required when more than one symbol is aggregated",
+            ));
+        }
+        let instrument = self
+            .registry
+            .resolve(symbol)
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        self.markets
+            .get(&instrument.name())
+            .ok_or_else(|| Status::not_found(format!("symbol {symbol} is not aggregated")))
+    }
+
+    /// Subscribes to the view selected by `request`, returns the subscription and the
+    /// address of the client
+    #[allow(clippy::result_large_err)]
+    fn subscribe(
+        &self,
+        request: &tonic::Request<SummaryRequest>,
+    ) -> Result<(Subscription, String), Status> {
+        let peer = request
+            .remote_addr()
+            .map(|address| address.to_string())
+            .unwrap_or_default();
+        let request = request.get_ref();
+        let aggregator = self.aggregator(&request.symbol)?;
+        let resume_after = (request.resume_after_sequence > 0).then_some(ResumePoint {
+            epoch: request.resume_epoch,
+            sequence: request.resume_after_sequence,
+        });
+        let subscription = aggregator
+            .views()
+            .subscribe(summary_view(request, self.max_depth)?, resume_after)?;
+        Ok((subscription, peer))
+    }
+}
+
+/// View of the summary selected by `request`, depth is limited to `max_depth`
+#[allow(clippy::result_large_err)]
+fn summary_view(request: &SummaryRequest, max_depth: usize) -> Result<SummaryView, Status> {
+    let exchanges = |names: &[String]| -> Result<Vec<Exchange>, Status> {
+        names
+            .iter()
+            .map(|name| {
+                Exchange::from_name(name)
+                    .ok_or_else(|| Status::invalid_argument(format!("unknown exchange {name}")))
+            })
+            .collect()
+    };
+    let depth = match request.depth as usize {
+        0 => max_depth,
+        depth => depth.min(max_depth),
+    };
+    Ok(SummaryView::new(
+        depth,
+        &exchanges(&request.include_exchanges)?,
+        &exchanges(&request.exclude_exchanges)?,
+    ))
 }
 
 #[tonic::async_trait]
 impl OrderbookAggregator for BookSummaryService {
-    type BookSummaryStream = Receiver<Result<Summary, tonic::Status>>;
+    type BookSummaryStream = SummaryStream;
     async fn book_summary(
         &self,
-        _request: tonic::Request<Empty>,
-    ) -> Result<tonic::Response<Self::BookSummaryStream>, tonic::Status> {
-        Ok(tonic::Response::new(self.receiver.activate_cloned()))
+        request: tonic::Request<SummaryRequest>,
+    ) -> Result<tonic::Response<Self::BookSummaryStream>, Status> {
+        let (subscription, peer) = self.subscribe(&request)?;
+        Ok(tonic::Response::new(self.clients.summary_stream(
+            subscription,
+            peer,
+            request.into_inner().symbol,
+            self.lag_policy,
+        )))
+    }
+    type BookSummaryDeltasStream = DeltaStream;
+    async fn book_summary_deltas(
+        &self,
+        request: tonic::Request<SummaryRequest>,
+    ) -> Result<tonic::Response<Self::BookSummaryDeltasStream>, Status> {
+        let (subscription, peer) = self.subscribe(&request)?;
+        Ok(tonic::Response::new(self.clients.delta_stream(
+            subscription,
+            peer,
+            request.into_inner().symbol,
+            self.lag_policy,
+        )))
+    }
+    async fn get_summary(
+        &self,
+        request: tonic::Request<SummaryRequest>,
+    ) -> Result<tonic::Response<Summary>, Status> {
+        let request = request.get_ref();
+        let aggregator = self.aggregator(&request.symbol)?;
+        let view = summary_view(request, self.max_depth)?;
+        Ok(tonic::Response::new(aggregator.summary(&view)))
+    }
+    async fn get_exchange_book(
+        &self,
+        request: tonic::Request<ExchangeBookRequest>,
+    ) -> Result<tonic::Response<ExchangeBook>, Status> {
+        let request = request.get_ref();
+        let aggregator = self.aggregator(&request.symbol)?;
+        let exchange = Exchange::from_name(&request.exchange).ok_or_else(|| {
+            Status::invalid_argument(format!("unknown exchange {}", request.exchange))
+        })?;
+        let book = aggregator.exchange_book(exchange).ok_or_else(|| {
+            Status::unavailable(format!("no current book of {}", exchange.name()))
+        })?;
+        Ok(tonic::Response::new(book))
+    }
+    type FeedStatusStream = Receiver<Result<FeedStatusReport, Status>>;
+    async fn feed_status(
+        &self,
+        _request: tonic::Request<FeedStatusRequest>,
+    ) -> Result<tonic::Response<Self::FeedStatusStream>, Status> {
+        Ok(tonic::Response::new(self.status.subscribe()))
+    }
+    async fn client_lag(
+        &self,
+        _request: tonic::Request<ClientLagRequest>,
+    ) -> Result<tonic::Response<ClientLagReport>, Status> {
+        Ok(tonic::Response::new(self.clients.report()))
+    }
+    type QuerySummariesStream = ReceiverStream<Result<Summary, Status>>;
+    async fn query_summaries(
+        &self,
+        request: tonic::Request<SummaryQuery>,
+    ) -> Result<tonic::Response<Self::QuerySummariesStream>, Status> {
+        // Summaries read ahead of the client
+        const READ_AHEAD: usize = 64;
+        let archive = self
+            .archive
+            .clone()
+            .ok_or_else(|| Status::failed_precondition("summaries are not archived"))?;
+        let query = request.into_inner();
+        let view_request = query.view.unwrap_or_default();
+        let market = self.aggregator(&view_request.symbol)?.name().to_string();
+        let view = summary_view(&view_request, self.max_depth)?;
+        let to_us = match query.to_timestamp_us {
+            0 => u64::MAX,
+            to_us => to_us,
+        };
+        if query.from_timestamp_us >= to_us {
+            return Err(Status::invalid_argument("time range is empty"));
+        }
+        let (sender, receiver) = mpsc::channel(READ_AHEAD);
+        tokio::task::spawn_blocking(move || {
+            let result = archive.query(
+                &market,
+                query.from_timestamp_us,
+                to_us,
+                query.interval_us,
+                |summary| {
+                    let (sequence, publish_timestamp_us) =
+                        (summary.sequence, summary.publish_timestamp_us);
+                    let selected = match FullSummary::parse(summary) {
+                        Ok(full) => Ok(Summary {
+                            sequence,
+                            publish_timestamp_us,
+                            ..view.select(&full)
+                        }),
+                        Err(e) => {
+                            error!(target : "SummaryArchive", "Archived summary of {market} is invalid: {e}");
+                            Err(Status::data_loss("archived summary is invalid"))
+                        }
+                    };
+                    let valid = selected.is_ok();
+                    sender.blocking_send(selected).is_ok() && valid
+                },
+            );
+            if let Err(e) = result {
+                error!(target : "SummaryArchive", "Query of {market} failed: {e}");
+                let _ = sender.blocking_send(Err(Status::internal("reading the archive failed")));
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::book_callback::BookCallback;
+    use crate::defines::grpc_scheme::orderbook_aggregator_server::OrderbookAggregator;
+    use crate::defines::grpc_scheme::SummaryRequest;
+    use crate::defines::instrument::{InstrumentRegistry, Market};
+    use crate::defines::Exchange;
+    use crate::grpc_server::BookSummaryService;
+    use crate::marketdata::{BookLevel, Orderbook};
+    use rust_decimal_macros::dec;
+    use tonic::Code;
+
+    fn service_of(specs: &[&str]) -> BookSummaryService {
+        let registry = InstrumentRegistry::with_defaults();
+        let markets: Vec<Market> = specs
+            .iter()
+            .map(|spec| Market::parse(spec, &registry).unwrap())
+            .collect();
+        BookSummaryService::without_feeds(&markets, registry, 10)
+    }
+
+    fn request(symbol: &str) -> tonic::Request<SummaryRequest> {
+        tonic::Request::new(SummaryRequest {
+            symbol: symbol.to_string(),
+            ..SummaryRequest::default()
+        })
+    }
+
+    fn book() -> Orderbook {
+        let level = |price| BookLevel {
+            price,
+            quantity: dec!(1),
+        };
+        Orderbook::new(
+            [level(dec!(100))].into_iter().collect(),
+            [level(dec!(101))].into_iter().collect(),
+        )
+    }
+
+    #[test]
+    fn symbols_select_the_market() {
+        let service = service_of(&["BTC/USDT", "ETH/USDT"]);
+        let market = |symbol| service.aggregator(symbol).unwrap().name().to_string();
+        assert_eq!(market("btcusdt"), "BTC/USDT");
+        assert_eq!(market("ETH/USDT"), "ETH/USDT");
+        let code = |symbol| service.aggregator(symbol).err().map(|status| status.code());
+        // Known instrument which is not aggregated and unknown instrument
+        assert_eq!(code("BTC/USD"), Some(Code::NotFound));
+        assert_eq!(code("FOO/BAR"), Some(Code::NotFound));
+        assert_eq!(code(""), Some(Code::InvalidArgument));
+
+        // The symbol may be omitted if only one market is aggregated
+        let single = service_of(&["BTC/USDT"]);
+        assert_eq!(single.aggregator("").unwrap().name(), "BTC/USDT");
+    }
+
+    #[tokio::test]
+    async fn every_market_has_its_own_views() {
+        let service = service_of(&["BTC/USDT", "ETH/USDT"]);
+        let (mut btc, _) = service.subscribe(&request("BTC/USDT")).unwrap();
+        let (eth, _) = service.subscribe(&request("ETH/USDT")).unwrap();
+        service
+            .aggregator("BTC/USDT")
+            .unwrap()
+            .accept_book(book(), Exchange::Binance)
+            .await;
+
+        let summary = btc.receiver.borrow_and_update().summary.clone().unwrap();
+        assert_eq!(summary.bids[0].price, 100.);
+        assert!(!eth.receiver.has_changed().unwrap());
+
+        let btc = service.get_summary(request("BTC/USDT")).await.unwrap();
+        assert_eq!(btc.into_inner().bids.len(), 1);
+        let eth = service.get_summary(request("ETH/USDT")).await.unwrap();
+        assert!(eth.into_inner().bids.is_empty());
+        let missing = service.get_summary(request("BTC/USD")).await.unwrap_err();
+        assert_eq!(missing.code(), Code::NotFound);
     }
 }
@@ -1,3 +1,5 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 pub(crate) fn is_descending_by_key<T, F, K>(values: &[T], f: F) -> bool
 where
     F: Fn(&T) -> K,
@@ -13,3 +15,67 @@ where
 {
     values.windows(2).all(|x| f(&x[0]) <= f(&x[1]))
 }
+
+/// Current time in microseconds since the unix epoch
+pub(crate) fn unix_time_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Microseconds since the unix epoch of an RFC 3339 UTC time such as
+/// `2023-06-20T10:00:00.100000Z`, digits beyond microseconds are ignored
+pub(crate) fn parse_utc_time_us(text: &str) -> Option<u64> {
+    let (date, time) = text.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|field| field.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(|field| field.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    let valid = (1..=12).contains(&month)
+        && (1..=31).contains(&day)
+        && hour < 24
+        && minute < 60
+        && second <= 60
+        && fraction.bytes().all(|digit| digit.is_ascii_digit());
+    if !valid {
+        return None;
+    }
+    let micros = fraction
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(6)
+        .fold(0, |micros, digit| micros * 10 + u64::from(digit - b'0'));
+    // Days since the epoch in the proleptic Gregorian calendar, years start in March so
+    // that leap days are the last day of a year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146_097 + day_of_era - 719_468).ok()?;
+    Some((((days * 24 + hour) * 60 + minute) * 60 + second) * 1_000_000 + micros)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::helper::parse_utc_time_us;
+
+    #[test]
+    fn utc_times_are_parsed() {
+        assert_eq!(parse_utc_time_us("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_utc_time_us("2023-06-20T10:00:00.100000Z"),
+            Some(1_687_255_200_100_000)
+        );
+        // Leap day, nanoseconds are truncated
+        assert_eq!(
+            parse_utc_time_us("2024-02-29T23:59:59.123456789Z"),
+            Some(1_709_251_199_123_456)
+        );
+        assert_eq!(parse_utc_time_us("2023-06-20T10:00:00.1"), None);
+        assert_eq!(parse_utc_time_us("2023-13-20T10:00:00Z"), None);
+        assert_eq!(parse_utc_time_us("1969-12-31T23:59:59Z"), None);
+    }
+}
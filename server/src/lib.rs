@@ -0,0 +1,10 @@
+mod app;
+mod archive;
+pub mod backtest;
+mod defines;
+mod feed;
+mod grpc_server;
+pub(crate) mod helper;
+mod marketdata;
+
+pub use app::{run, Args};
@@ -1,11 +1,11 @@
+use crate::archive::MarketArchive;
 use crate::defines::book_callback::BookCallback;
-use crate::defines::grpc_scheme::{Level, Summary};
-use crate::defines::{Exchange, BOOK_LEVELS_USED};
-use crate::helper::{is_ascending_by_key, is_descending_by_key};
-use async_broadcast::Sender;
+use crate::defines::grpc_scheme::{ExchangeBook, Level, Summary};
+use crate::defines::instrument::{Conversion, Market};
+use crate::defines::Exchange;
+use crate::helper::{is_ascending_by_key, is_descending_by_key, unix_time_us};
 use async_trait::async_trait;
 use halfbrown::HashMap;
-use log::error;
 use parking_lot::Mutex;
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
@@ -13,7 +13,16 @@ use serde::Deserialize;
 use smallvec::SmallVec;
 use std::cmp::{Ordering, Reverse};
 use std::collections::BinaryHeap;
-use tonic::Status;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time::MissedTickBehavior;
+
+mod views;
+
+pub(crate) use views::{
+    same_levels, ResumePoint, ResumeWindow, Subscription, SummaryView, SummaryViews, ViewState,
+};
 
 #[derive(Debug, Copy, Clone, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
 pub(crate) struct BookLevel {
@@ -21,69 +30,599 @@ pub(crate) struct BookLevel {
     pub quantity: Decimal,
 }
 
+// Books with up to this many levels per side are passed on without heap allocations
+pub(crate) const INLINE_BOOK_LEVELS: usize = 10;
+
+pub(crate) type BookLevels = SmallVec<[BookLevel; INLINE_BOOK_LEVELS]>;
+
 #[derive(Debug, Clone)]
 pub(crate) struct Orderbook {
-    bids: SmallVec<[BookLevel; BOOK_LEVELS_USED]>,
-    asks: SmallVec<[BookLevel; BOOK_LEVELS_USED]>,
+    bids: BookLevels,
+    asks: BookLevels,
+    // Time of the exchange's event in microseconds since the unix epoch
+    exchange_time_us: Option<u64>,
+    // Time the message containing the book was received in microseconds since the unix epoch
+    received_at_us: u64,
 }
 
 impl Orderbook {
-    pub fn new(
-        bids: SmallVec<[BookLevel; BOOK_LEVELS_USED]>,
-        asks: SmallVec<[BookLevel; BOOK_LEVELS_USED]>,
-    ) -> Self {
+    pub fn new(bids: BookLevels, asks: BookLevels) -> Self {
         debug_assert!(is_descending_by_key(bids.as_slice(), |level| level.price));
         debug_assert!(is_ascending_by_key(asks.as_slice(), |level| level.price));
-        Self { bids, asks }
+        Self {
+            bids,
+            asks,
+            exchange_time_us: None,
+            received_at_us: 0,
+        }
+    }
+    pub fn with_exchange_time(mut self, exchange_time_us: u64) -> Self {
+        self.exchange_time_us = Some(exchange_time_us);
+        self
+    }
+    pub fn set_received_at(&mut self, received_at_us: u64) {
+        self.received_at_us = received_at_us;
+    }
+    /// Multiplies all prices with `rate`, the book stays sorted as long as `rate` is positive
+    pub fn convert(&self, rate: Decimal) -> Self {
+        let convert = |level: &BookLevel| BookLevel {
+            price: level.price * rate,
+            quantity: level.quantity,
+        };
+        Self {
+            bids: self.bids.iter().map(convert).collect(),
+            asks: self.asks.iter().map(convert).collect(),
+            ..*self
+        }
+    }
+    /// Bids ordered from best (highest) to worst
+    #[cfg(test)]
+    pub fn bids(&self) -> &[BookLevel] {
+        &self.bids
+    }
+    /// Asks ordered from best (lowest) to worst
+    #[cfg(test)]
+    pub fn asks(&self) -> &[BookLevel] {
+        &self.asks
+    }
+    #[cfg(test)]
+    pub fn exchange_time_us(&self) -> Option<u64> {
+        self.exchange_time_us
+    }
+    /// Drops all levels beyond `depth` on both sides
+    pub fn truncate(&mut self, depth: usize) {
+        self.bids.truncate(depth);
+        self.asks.truncate(depth);
+    }
+    /// Midpoint of the best bid and ask
+    pub fn mid_price(&self) -> Option<Decimal> {
+        Some((self.bids.first()?.price + self.asks.first()?.price) / Decimal::TWO)
     }
 }
 
 pub(crate) struct BookAggregatorCallback {
+    // Name of the market
+    name: String,
     aggregator: Mutex<BookAggregator>,
-    sender: Sender<Result<Summary, Status>>,
+    views: Arc<SummaryViews>,
+    // Quote asset of the market, books of other quotes are converted into it
+    quote: String,
+    // Locked after the aggregator if both are held
+    throttle: Mutex<PublishThrottle>,
+    // Wakes the task which publishes deferred updates
+    flush: Arc<Notify>,
+    archive: Option<Mutex<MarketArchive>>,
+    // Exchanges whose levels were stale at the last staleness check
+    stale_exchanges: Mutex<Vec<Exchange>>,
 }
 
 impl BookAggregatorCallback {
-    pub fn new(sender: Sender<Result<Summary, Status>>) -> Self {
+    pub fn new(views: Arc<SummaryViews>, market: &Market, stale_after: Option<Duration>) -> Self {
         Self {
-            aggregator: Mutex::new(BookAggregator::new()),
-            sender,
+            name: market.name.clone(),
+            aggregator: Mutex::new(BookAggregator::for_market(market, stale_after)),
+            views,
+            quote: market.quote.clone(),
+            throttle: Mutex::new(PublishThrottle::new(Duration::ZERO)),
+            flush: Arc::new(Notify::new()),
+            archive: None,
+            stale_exchanges: Mutex::new(Vec::new()),
+        }
+    }
+    /// Every published summary is also passed to `archive`
+    pub fn with_archive(mut self, archive: MarketArchive) -> Self {
+        self.archive = Some(Mutex::new(archive));
+        self
+    }
+    /// Publishes at most one summary per `min_interval`, books received in between are
+    /// coalesced into the next summary
+    pub fn limit_publish_rate(self: &Arc<Self>, min_interval: Duration) {
+        if min_interval.is_zero() {
+            return;
+        }
+        *self.throttle.lock() = PublishThrottle::new(min_interval);
+        let callback = Arc::downgrade(self);
+        let flush = self.flush.clone();
+        tokio::spawn(async move {
+            loop {
+                flush.notified().await;
+                let Some(due) = callback
+                    .upgrade()
+                    .map(|callback| callback.throttle.lock().due())
+                else {
+                    return;
+                };
+                tokio::time::sleep_until(due.into()).await;
+                match callback.upgrade() {
+                    Some(callback) => callback.publish_pending(),
+                    None => return,
+                }
+            }
+        });
+    }
+    /// Checks the books every half of the stale threshold and re-publishes the summary once
+    /// levels became stale, which would otherwise only be noticed with the next book
+    pub fn watch_staleness(self: &Arc<Self>) {
+        let Some(stale_after) = self.aggregator.lock().stale_after else {
+            return;
+        };
+        let period = (stale_after / 2).max(Duration::from_millis(1));
+        let callback = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                match callback.upgrade() {
+                    Some(callback) => callback.publish_stale(),
+                    None => return,
+                }
+            }
+        });
+    }
+    /// Archives levels which changed within the archive's minimum interval once it
+    /// expired, also if no further summary is published
+    pub fn watch_archive(self: &Arc<Self>) {
+        let Some(min_interval) = self
+            .archive
+            .as_ref()
+            .map(|archive| archive.lock().min_interval())
+            .filter(|min_interval| !min_interval.is_zero())
+        else {
+            return;
+        };
+        let callback = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(min_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(callback) = callback.upgrade() else {
+                    return;
+                };
+                if let Some(archive) = &callback.archive {
+                    archive.lock().flush_pending();
+                }
+            }
+        });
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn views(&self) -> &Arc<SummaryViews> {
+        &self.views
+    }
+    /// Current summary of `view`, computed on request
+    pub fn summary(&self, view: &SummaryView) -> Summary {
+        let full = self.aggregator.lock().make_full_summary();
+        Summary {
+            publish_timestamp_us: unix_time_us(),
+            epoch: self.views.epoch(),
+            ..view.select(&full)
+        }
+    }
+    /// Latest book of `exchange` before conversion, `None` if there is no current book
+    pub fn exchange_book(&self, exchange: Exchange) -> Option<ExchangeBook> {
+        let locked = self.aggregator.lock();
+        let quote = locked
+            .source_quote(exchange)
+            .unwrap_or(&self.quote)
+            .to_string();
+        let (bids, asks) = locked.raw_levels(exchange)?;
+        Some(ExchangeBook {
+            exchange: exchange.name().to_string(),
+            quote,
+            bids,
+            asks,
+        })
+    }
+    /// Converted books are re-published with a changed rate
+    pub fn set_rate(&self, quote: &str, rate: Decimal) {
+        let mut locked = self.aggregator.lock();
+        if locked.set_rate(quote, rate) {
+            self.publish(&locked);
+        }
+    }
+    pub fn remove_rate(&self, quote: &str) {
+        let mut locked = self.aggregator.lock();
+        if locked.remove_rate(quote) {
+            self.publish(&locked);
+        }
+    }
+    /// Publishes the summary of `aggregator` unless the last one was published within the
+    /// minimum interval, the caller has to hold the aggregator's lock
+    fn publish(&self, aggregator: &BookAggregator) {
+        if self.throttle.lock().try_publish(Instant::now()) {
+            // Published while locked so that sequence numbers follow the order of the books
+            self.publish_summary(&aggregator.make_full_summary());
+        } else {
+            self.flush.notify_one();
+        }
+    }
+    fn publish_pending(&self) {
+        let locked = self.aggregator.lock();
+        if self.throttle.lock().take_pending(Instant::now()) {
+            self.publish_summary(&locked.make_full_summary());
+        }
+    }
+    fn publish_stale(&self) {
+        let locked = self.aggregator.lock();
+        let stale = locked.stale_exchanges(Instant::now());
+        let mut last_stale = self.stale_exchanges.lock();
+        if *last_stale != stale {
+            *last_stale = stale;
+            self.publish(&locked);
+        }
+    }
+    fn publish_summary(&self, full: &FullSummary) {
+        self.views.publish(full);
+        if let Some(archive) = &self.archive {
+            archive.lock().archive(full);
+        }
+    }
+}
+
+impl Drop for BookAggregatorCallback {
+    fn drop(&mut self) {
+        // Ends the publishing task
+        self.flush.notify_one();
+    }
+}
+
+/// Tracks when summaries may be published again
+#[derive(Debug)]
+struct PublishThrottle {
+    min_interval: Duration,
+    last_publish: Option<Instant>,
+    // Set if an update was deferred
+    pending: bool,
+}
+
+impl PublishThrottle {
+    fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last_publish: None,
+            pending: false,
+        }
+    }
+    /// Whether an update can be published at `now`, otherwise it is marked as pending
+    fn try_publish(&mut self, now: Instant) -> bool {
+        let throttled = self
+            .last_publish
+            .is_some_and(|last_publish| now < last_publish + self.min_interval);
+        if throttled {
+            self.pending = true;
+            return false;
+        }
+        self.last_publish = Some(now);
+        self.pending = false;
+        true
+    }
+    /// Whether a deferred update has to be published at `now`
+    fn take_pending(&mut self, now: Instant) -> bool {
+        self.pending && self.try_publish(now)
+    }
+    /// Earliest time of the next summary
+    fn due(&self) -> Instant {
+        self.last_publish.map_or_else(Instant::now, |last_publish| {
+            last_publish + self.min_interval
+        })
+    }
+}
+
+/// Feeds the mid price of a conversion instrument into the aggregator as conversion rate
+#[derive(Clone)]
+pub(crate) struct ConversionRateCallback {
+    aggregator: Arc<BookAggregatorCallback>,
+    conversion: Conversion,
+}
+
+impl ConversionRateCallback {
+    pub fn new(aggregator: Arc<BookAggregatorCallback>, conversion: Conversion) -> Self {
+        Self {
+            aggregator,
+            conversion,
         }
     }
 }
 
+#[async_trait]
+impl BookCallback for ConversionRateCallback {
+    async fn accept_book(&self, book: Orderbook, _exchange: Exchange) {
+        if let Some(rate) = conversion_rate(&self.conversion, &book) {
+            self.aggregator.set_rate(&self.conversion.quote, rate);
+        }
+    }
+    async fn disconnected(&self, _exchange: Exchange) {
+        self.aggregator.remove_rate(&self.conversion.quote);
+    }
+}
+
 #[async_trait]
 impl BookCallback for BookAggregatorCallback {
     async fn accept_book(&self, book: Orderbook, exchange: Exchange) {
-        let message = {
-            let mut locked = self.aggregator.lock();
-            locked.add_new_book(book, exchange);
-            locked.make_summary()
+        let mut locked = self.aggregator.lock();
+        locked.add_new_book(book, exchange);
+        self.publish(&locked);
+    }
+    async fn disconnected(&self, exchange: Exchange) {
+        let mut locked = self.aggregator.lock();
+        locked.remove_book(exchange);
+        self.publish(&locked);
+    }
+}
+
+/// Rate into the market's quote asset given by the mid price of the conversion instrument's
+/// book, `None` if the book has no mid price
+pub(crate) fn conversion_rate(conversion: &Conversion, book: &Orderbook) -> Option<Decimal> {
+    let mid_price = book.mid_price().filter(|price| !price.is_zero())?;
+    Some(if conversion.inverted {
+        Decimal::ONE / mid_price
+    } else {
+        mid_price
+    })
+}
+
+/// Level of `book`, whose prices were multiplied with `rate` if it is set
+fn make_level(
+    level: &BookLevel,
+    exchange: Exchange,
+    book: &Orderbook,
+    rate: Option<Decimal>,
+    stale: bool,
+) -> Level {
+    let conversion_rate = rate.unwrap_or(Decimal::ONE);
+    Level {
+        exchange: exchange.name().to_string(),
+        price: level.price.to_f64().unwrap_or_default(),
+        amount: level.quantity.to_f64().unwrap_or_default(),
+        converted: rate.is_some(),
+        conversion_rate: conversion_rate.to_f64().unwrap_or(1.),
+        stale,
+        price_exact: level.price.to_string(),
+        amount_exact: level.quantity.to_string(),
+        conversion_rate_exact: conversion_rate.to_string(),
+        exchange_timestamp_us: book.exchange_time_us.unwrap_or_default(),
+        receive_timestamp_us: book.received_at_us,
+    }
+}
+
+/// Level of an aggregated book along with its exact price
+#[derive(Debug, Clone)]
+pub(crate) struct PricedLevel {
+    pub price: Decimal,
+    pub level: Level,
+}
+
+/// Sorted levels of all aggregated books, the summaries of all views are selected from it
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FullSummary {
+    pub bids: Vec<PricedLevel>,
+    pub asks: Vec<PricedLevel>,
+}
+
+impl FullSummary {
+    /// Levels of an archived summary, fails if a price is not a decimal
+    pub fn parse(summary: Summary) -> Result<Self, rust_decimal::Error> {
+        let parse = |levels: Vec<Level>| {
+            levels
+                .into_iter()
+                .map(|level| {
+                    Ok(PricedLevel {
+                        price: level.price_exact.parse()?,
+                        level,
+                    })
+                })
+                .collect::<Result<Vec<_>, rust_decimal::Error>>()
         };
-        let send_result = self.sender.broadcast(Ok(message)).await;
-        // Overflow should not happen but we should be able to see if it happens
-        // Error can be ignored because that only means that currently no one is subscribed
-        if let Ok(Some(_)) = send_result {
-            error!(target : "BookAggregatorCallback", "broadcast channel overflowed");
-        }
+        Ok(Self {
+            bids: parse(summary.bids)?,
+            asks: parse(summary.asks)?,
+        })
+    }
+    pub fn into_summary(self) -> Summary {
+        summary_of_levels(self.bids, self.asks)
+    }
+}
+
+/// Summary of sorted levels, the spread is computed from the exact prices
+pub(crate) fn summary_of_levels(
+    bids: impl IntoIterator<Item = PricedLevel>,
+    asks: impl IntoIterator<Item = PricedLevel>,
+) -> Summary {
+    let mut bids = bids.into_iter().peekable();
+    let mut asks = asks.into_iter().peekable();
+    let spread = match (bids.peek(), asks.peek()) {
+        (Some(bid), Some(ask)) => ask.price - bid.price,
+        _ => Decimal::ZERO,
+    };
+    Summary {
+        spread: spread.to_f64().unwrap_or_default(),
+        bids: bids.map(|priced| priced.level).collect(),
+        asks: asks.map(|priced| priced.level).collect(),
+        spread_exact: spread.to_string(),
+        sequence: 0,
+        publish_timestamp_us: 0,
+        epoch: 0,
     }
 }
 
 #[derive(Debug)]
-pub(crate) struct BookAggregator<const N_LEVELS: usize = BOOK_LEVELS_USED> {
+pub(crate) struct BookAggregator {
+    // Books in the market's quote asset, these are aggregated
     books: HashMap<Exchange, Orderbook>,
+    // Quote asset of exchanges whose books have to be converted
+    source_quotes: HashMap<Exchange, String>,
+    // Latest rates into the market's quote asset by quote asset
+    rates: HashMap<String, Decimal>,
+    // Unconverted books of exchanges in `source_quotes`
+    raw_books: HashMap<Exchange, Orderbook>,
+    // Time the latest book of every exchange was added, staleness is measured from it
+    received_at: HashMap<Exchange, Instant>,
+    // Levels of books older than this are flagged as stale
+    stale_after: Option<Duration>,
 }
 
-impl<const N_LEVELS: usize> BookAggregator<N_LEVELS> {
+impl BookAggregator {
+    /// Aggregator of the sources of `market`
+    pub fn for_market(market: &Market, stale_after: Option<Duration>) -> Self {
+        let mut aggregator = Self::new();
+        aggregator.set_stale_after(stale_after);
+        for (exchange, instrument) in &market.sources {
+            if instrument.quote != market.quote {
+                aggregator.set_source_quote(*exchange, &instrument.quote);
+            }
+        }
+        aggregator
+    }
+    /// Books of `exchange` are quoted in `quote` and only aggregated once a rate is known
+    pub fn set_source_quote(&mut self, exchange: Exchange, quote: &str) {
+        self.source_quotes.insert(exchange, quote.to_string());
+    }
+    pub fn set_stale_after(&mut self, stale_after: Option<Duration>) {
+        self.stale_after = stale_after;
+    }
+    /// Converts the books of `quote` with `rate`, returns false if the rate did not change
+    pub fn set_rate(&mut self, quote: &str, rate: Decimal) -> bool {
+        if self.rates.insert(quote.to_string(), rate) == Some(rate) {
+            return false;
+        }
+        for (exchange, book) in self.raw_books.iter() {
+            if self.source_quotes.get(exchange).map(String::as_str) == Some(quote) {
+                self.books.insert(*exchange, book.convert(rate));
+            }
+        }
+        true
+    }
+    /// Converted books of `quote` are no longer aggregated until a new rate is set, returns
+    /// false if there was no rate
+    pub fn remove_rate(&mut self, quote: &str) -> bool {
+        if self.rates.remove(quote).is_none() {
+            return false;
+        }
+        for (exchange, source_quote) in self.source_quotes.iter() {
+            if source_quote == quote {
+                self.books.remove(exchange);
+            }
+        }
+        true
+    }
+    /// Evicts the book of `exchange`, e.g. because its feed disconnected
+    pub fn remove_book(&mut self, exchange: Exchange) {
+        self.books.remove(&exchange);
+        self.raw_books.remove(&exchange);
+        self.received_at.remove(&exchange);
+    }
     pub fn add_new_book(&mut self, book: Orderbook, exchange: Exchange) {
-        self.books.insert(exchange, book);
+        self.add_new_book_at(book, exchange, Instant::now());
+    }
+    /// Adds `book` as received at `now`, the time staleness is measured from
+    pub fn add_new_book_at(&mut self, book: Orderbook, exchange: Exchange, now: Instant) {
+        self.received_at.insert(exchange, now);
+        let Some(quote) = self.source_quotes.get(&exchange) else {
+            self.books.insert(exchange, book);
+            return;
+        };
+        if let Some(rate) = self.rates.get(quote) {
+            self.books.insert(exchange, book.convert(*rate));
+        }
+        self.raw_books.insert(exchange, book);
+    }
+    pub fn source_quote(&self, exchange: Exchange) -> Option<&str> {
+        self.source_quotes.get(&exchange).map(String::as_str)
+    }
+    /// Levels of the book of `exchange` as received, i.e. without conversion
+    pub fn raw_levels(&self, exchange: Exchange) -> Option<(Vec<Level>, Vec<Level>)> {
+        let book = self
+            .raw_books
+            .get(&exchange)
+            .or_else(|| self.books.get(&exchange))?;
+        let stale = self.is_stale(exchange, Instant::now());
+        let make_levels = |levels: &[BookLevel]| {
+            levels
+                .iter()
+                .map(|level| make_level(level, exchange, book, None, stale))
+                .collect()
+        };
+        Some((make_levels(&book.bids), make_levels(&book.asks)))
     }
-    pub fn make_summary(&self) -> Summary {
+    /// Exchanges whose aggregated levels are stale at `now`, in the order of `Exchange::ALL`
+    pub fn stale_exchanges(&self, now: Instant) -> Vec<Exchange> {
+        Exchange::ALL
+            .into_iter()
+            .filter(|exchange| self.books.contains_key(exchange) && self.is_stale(*exchange, now))
+            .collect()
+    }
+    fn is_stale(&self, exchange: Exchange, now: Instant) -> bool {
+        match (self.stale_after, self.received_at.get(&exchange)) {
+            (Some(stale_after), Some(received_at)) => {
+                now.saturating_duration_since(*received_at) >= stale_after
+            }
+            _ => false,
+        }
+    }
+    fn make_aggregated_level(
+        &self,
+        level: &BookLevel,
+        exchange: Exchange,
+        book: &Orderbook,
+        now: Instant,
+    ) -> PricedLevel {
+        let rate = self
+            .source_quotes
+            .get(&exchange)
+            .and_then(|quote| self.rates.get(quote));
+        PricedLevel {
+            price: level.price,
+            level: make_level(
+                level,
+                exchange,
+                book,
+                rate.copied(),
+                self.is_stale(exchange, now),
+            ),
+        }
+    }
+    /// Every level of all books, views of clients are selected from it
+    pub fn make_full_summary(&self) -> FullSummary {
+        self.priced_levels_at(usize::MAX, Instant::now())
+    }
+    /// Summary of the best `depth` levels per side over all books
+    #[cfg(test)]
+    pub fn make_summary(&self, depth: usize) -> Summary {
+        self.make_summary_at(depth, Instant::now())
+    }
+    /// Summary of the best `depth` levels per side, staleness is evaluated at `now`
+    pub fn make_summary_at(&self, depth: usize, now: Instant) -> Summary {
+        self.priced_levels_at(depth, now).into_summary()
+    }
+    fn priced_levels_at(&self, depth: usize, now: Instant) -> FullSummary {
         #[derive(PartialEq, Eq)]
         struct BookLevelAndExchangeHelper<'a>(&'a BookLevel, Exchange);
         impl<'a> PartialOrd for BookLevelAndExchangeHelper<'a> {
             fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-                self.0.partial_cmp(other.0)
+                Some(self.cmp(other))
             }
         }
         impl<'a> Ord for BookLevelAndExchangeHelper<'a> {
@@ -91,7 +630,12 @@ impl<const N_LEVELS: usize> BookAggregator<N_LEVELS> {
                 self.0.cmp(other.0)
             }
         }
-        let expected_number_of_entries = N_LEVELS;
+        let expected_number_of_entries = self
+            .books
+            .values()
+            .map(|book| book.bids.len().max(book.asks.len()))
+            .sum::<usize>()
+            .min(depth);
         let mut bids = Vec::with_capacity(expected_number_of_entries);
         let mut asks = Vec::with_capacity(expected_number_of_entries);
         let mut bid_indices: HashMap<Exchange, usize> = HashMap::new();
@@ -110,52 +654,54 @@ impl<const N_LEVELS: usize> BookAggregator<N_LEVELS> {
                 ask_heap.push(Reverse(BookLevelAndExchangeHelper(level, *exchange)));
             }
         }
-        for _ in 0..N_LEVELS {
+        for _ in 0..depth {
+            if bid_heap.is_empty() && ask_heap.is_empty() {
+                break;
+            }
             if let Some(BookLevelAndExchangeHelper(level, exchange)) = bid_heap.pop() {
-                bids.push(Level {
-                    exchange: exchange.name().to_string(),
-                    price: level.price.to_f64().unwrap_or_default(),
-                    amount: level.quantity.to_f64().unwrap_or_default(),
-                });
+                let book = self.books.get(&exchange).unwrap();
+                bids.push(self.make_aggregated_level(level, exchange, book, now));
                 let index = bid_indices.remove(&exchange).unwrap();
-                if let Some(bid_level) = self.books.get(&exchange).unwrap().bids.get(index) {
+                if let Some(bid_level) = book.bids.get(index) {
                     bid_heap.push(BookLevelAndExchangeHelper(bid_level, exchange));
                     bid_indices.insert_nocheck(exchange, index + 1);
                 }
             }
             if let Some(Reverse(BookLevelAndExchangeHelper(level, exchange))) = ask_heap.pop() {
+                let book = self.books.get(&exchange).unwrap();
                 let index = ask_indices.remove(&exchange).unwrap();
-                asks.push(Level {
-                    exchange: exchange.name().to_string(),
-                    price: level.price.to_f64().unwrap_or_default(),
-                    amount: level.quantity.to_f64().unwrap_or_default(),
-                });
-                if let Some(ask_level) = self.books.get(&exchange).unwrap().asks.get(index) {
+                asks.push(self.make_aggregated_level(level, exchange, book, now));
+                if let Some(ask_level) = book.asks.get(index) {
                     ask_heap.push(Reverse(BookLevelAndExchangeHelper(ask_level, exchange)));
 
                     ask_indices.insert_nocheck(exchange, index + 1);
                 }
             }
         }
-        let spread = if asks.first().is_some() && bids.first().is_some() {
-            asks.first().as_ref().unwrap().price - bids.first().as_ref().unwrap().price
-        } else {
-            0.
-        };
-        Summary { spread, asks, bids }
+        FullSummary { bids, asks }
     }
     pub fn new() -> Self {
         Self {
             books: HashMap::new(),
+            source_quotes: HashMap::new(),
+            rates: HashMap::new(),
+            raw_books: HashMap::new(),
+            received_at: HashMap::new(),
+            stale_after: None,
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::defines::book_callback::BookCallback;
     use crate::defines::grpc_scheme::Summary;
-    use crate::defines::{Exchange, BOOK_LEVELS_USED};
-    use crate::marketdata::{BookAggregator, BookLevel, Orderbook};
+    use crate::defines::instrument::{InstrumentRegistry, Market};
+    use crate::defines::Exchange;
+    use crate::marketdata::{
+        BookAggregator, BookAggregatorCallback, BookLevel, BookLevels, ConversionRateCallback,
+        Orderbook, PublishThrottle, SummaryView, SummaryViews,
+    };
     use float_cmp::approx_eq;
     use halfbrown::HashMap;
     use rand::distributions::Distribution;
@@ -165,8 +711,9 @@ mod test {
     use rust_decimal::prelude::ToPrimitive;
     use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
-    use smallvec::SmallVec;
     use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
 
     const TEST_BOOKS_SIZE: usize = 10;
 
@@ -209,7 +756,7 @@ mod test {
             result
         }
 
-        fn prices_to_level_stubs(prices: Vec<Decimal>) -> SmallVec<[BookLevel; BOOK_LEVELS_USED]> {
+        fn prices_to_level_stubs(prices: Vec<Decimal>) -> BookLevels {
             prices
                 .into_iter()
                 .map(|price| BookLevel {
@@ -219,11 +766,7 @@ mod test {
                 .collect()
         }
 
-        fn generate_random_book_levels(
-            &mut self,
-            n: usize,
-            is_ask: bool,
-        ) -> SmallVec<[BookLevel; BOOK_LEVELS_USED]> {
+        fn generate_random_book_levels(&mut self, n: usize, is_ask: bool) -> BookLevels {
             let prices = if is_ask {
                 let mut prices = self.generate_random_unique_prices(n, dec!(1), dec!(999));
                 prices.sort();
@@ -237,7 +780,7 @@ mod test {
         }
 
         fn generate_random_book(&mut self, n: usize, exchange: Exchange) -> Orderbook {
-            assert!(2 * n == self.price_to_exchange.len() || self.price_to_exchange.len() == 0);
+            assert!(2 * n == self.price_to_exchange.len() || self.price_to_exchange.is_empty());
             let bids = self.generate_random_book_levels(n, false);
             let asks = self.generate_random_book_levels(n, true);
             let n_prior = self.price_to_exchange.len();
@@ -247,7 +790,7 @@ mod test {
             }
             assert_eq!(n_prior + 2 * n, self.price_to_exchange.len());
 
-            Orderbook { bids, asks }
+            Orderbook::new(bids, asks)
         }
 
         fn replace_book_with_new_random_one(
@@ -340,6 +883,24 @@ mod test {
         }
     }
 
+    #[test]
+    fn publishing_is_throttled() {
+        let mut throttle = PublishThrottle::new(Duration::from_millis(100));
+        let start = Instant::now();
+        assert!(throttle.try_publish(start));
+        assert!(!throttle.try_publish(start + Duration::from_millis(40)));
+        assert!(!throttle.try_publish(start + Duration::from_millis(80)));
+        assert_eq!(throttle.due(), start + Duration::from_millis(100));
+        assert!(!throttle.take_pending(start + Duration::from_millis(90)));
+        assert!(throttle.take_pending(start + Duration::from_millis(100)));
+        // Nothing left to publish
+        assert!(!throttle.take_pending(start + Duration::from_millis(300)));
+
+        let mut unlimited = PublishThrottle::new(Duration::ZERO);
+        assert!(unlimited.try_publish(start));
+        assert!(unlimited.try_publish(start));
+    }
+
     // ensure that PartialOrd orders book levels by price first
     #[test]
     fn book_levels_ordered_by_price() {
@@ -365,9 +926,206 @@ mod test {
         }
     }
 
+    fn book(bid: Decimal, ask: Decimal) -> Orderbook {
+        let level = |price| BookLevel {
+            price,
+            quantity: dec!(1),
+        };
+        Orderbook::new(
+            [level(bid)].into_iter().collect(),
+            [level(ask)].into_iter().collect(),
+        )
+    }
+
+    #[test]
+    fn foreign_quoted_books_are_converted() {
+        let mut aggregator = BookAggregator::new();
+        aggregator.set_source_quote(Exchange::Binance, "USDT");
+        aggregator.add_new_book(book(dec!(100), dec!(102)), Exchange::Bitstamp);
+        aggregator.add_new_book(book(dec!(100), dec!(101)), Exchange::Binance);
+        // Without a rate the USDT book cannot be merged
+        assert_eq!(aggregator.make_summary(TEST_BOOKS_SIZE).bids.len(), 1);
+
+        aggregator.set_rate("USDT", dec!(1.01));
+        let summary = aggregator.make_summary(TEST_BOOKS_SIZE);
+        let best_bid = &summary.bids[0];
+        assert_eq!(best_bid.exchange, Exchange::Binance.name());
+        assert!(best_bid.converted);
+        assert!(approx_eq!(f64, best_bid.price, 101., epsilon = 0.00001));
+        assert!(approx_eq!(
+            f64,
+            best_bid.conversion_rate,
+            1.01,
+            epsilon = 0.00001
+        ));
+        let best_ask = &summary.asks[0];
+        assert_eq!(best_ask.exchange, Exchange::Bitstamp.name());
+        assert!(!best_ask.converted);
+        assert!(approx_eq!(f64, summary.spread, 1., epsilon = 0.00001));
+        assert_eq!(best_bid.price_exact, "101.00");
+        assert_eq!(best_bid.conversion_rate_exact, "1.01");
+        assert_eq!(best_ask.conversion_rate_exact, "1");
+        assert_eq!(summary.spread_exact, "1.00");
+
+        // Exchange books are returned as received
+        let (bids, _) = aggregator.raw_levels(Exchange::Binance).unwrap();
+        assert_eq!(bids[0].price_exact, "100");
+        assert!(!bids[0].converted);
+        assert_eq!(aggregator.source_quote(Exchange::Binance), Some("USDT"));
+        assert!(aggregator.raw_levels(Exchange::Kraken).is_none());
+    }
+
+    #[tokio::test]
+    async fn rate_changes_are_published() {
+        let registry = InstrumentRegistry::with_defaults();
+        let market = Market::parse("BTC/USD;binance=BTC/USDT", &registry).unwrap();
+        let views = Arc::new(SummaryViews::new());
+        let callback = Arc::new(BookAggregatorCallback::new(views.clone(), &market, None));
+        let conversion = market.conversions[0].clone();
+        let rates = ConversionRateCallback::new(callback.clone(), conversion.clone());
+        let mut receiver = views
+            .subscribe(SummaryView::all(TEST_BOOKS_SIZE), None)
+            .unwrap()
+            .receiver;
+        callback
+            .accept_book(book(dec!(100), dec!(102)), Exchange::Binance)
+            .await;
+        let mut binance_bid = || {
+            let state = receiver.borrow_and_update();
+            let summary = state.summary.as_ref().unwrap();
+            summary
+                .bids
+                .iter()
+                .find(|level| level.exchange == Exchange::Binance.name())
+                .map(|level| level.price_exact.clone())
+        };
+        assert_eq!(binance_bid(), None);
+
+        rates
+            .accept_book(book(dec!(1.00), dec!(1.02)), conversion.exchange)
+            .await;
+        assert_eq!(binance_bid().unwrap(), "101.00");
+        // The same rate is not published again
+        rates
+            .accept_book(book(dec!(0.99), dec!(1.03)), conversion.exchange)
+            .await;
+        assert!(!receiver.has_changed().unwrap());
+
+        rates.disconnected(conversion.exchange).await;
+        assert!(receiver.has_changed().unwrap());
+    }
+
+    #[test]
+    fn levels_carry_book_timestamps() {
+        let mut aggregator = BookAggregator::new();
+        let mut kraken_book = book(dec!(100), dec!(102)).with_exchange_time(1_000);
+        kraken_book.set_received_at(1_500);
+        aggregator.add_new_book(kraken_book, Exchange::Kraken);
+        aggregator.add_new_book(book(dec!(99), dec!(101)), Exchange::Coinbase);
+        let summary = aggregator.make_summary(TEST_BOOKS_SIZE);
+        let kraken = &summary.bids[0];
+        assert_eq!(
+            (kraken.exchange_timestamp_us, kraken.receive_timestamp_us),
+            (1_000, 1_500)
+        );
+        assert_eq!(summary.asks[0].exchange_timestamp_us, 0);
+    }
+
+    #[test]
+    fn exact_prices_of_small_ticks() {
+        let mut aggregator = BookAggregator::new();
+        aggregator.add_new_book(
+            book(dec!(0.000000123456789012), dec!(0.000000123456789013)),
+            Exchange::Kraken,
+        );
+        let summary = aggregator.make_summary(TEST_BOOKS_SIZE);
+        assert_eq!(summary.bids[0].price_exact, "0.000000123456789012");
+        assert_eq!(summary.asks[0].amount_exact, "1");
+        assert_eq!(summary.spread_exact, "0.000000000000000001");
+    }
+
+    #[test]
+    fn disconnected_and_outdated_books() {
+        let mut aggregator = BookAggregator::new();
+        aggregator.add_new_book(book(dec!(100), dec!(102)), Exchange::Bitstamp);
+        aggregator.add_new_book(book(dec!(101), dec!(103)), Exchange::Kraken);
+        assert!(aggregator
+            .make_summary(TEST_BOOKS_SIZE)
+            .bids
+            .iter()
+            .all(|level| !level.stale));
+
+        aggregator.remove_book(Exchange::Kraken);
+        let summary = aggregator.make_summary(TEST_BOOKS_SIZE);
+        assert_eq!(summary.bids.len(), 1);
+        assert_eq!(summary.bids[0].exchange, Exchange::Bitstamp.name());
+
+        aggregator.set_stale_after(Some(Duration::ZERO));
+        assert!(aggregator.make_summary(TEST_BOOKS_SIZE).bids[0].stale);
+    }
+
+    #[tokio::test]
+    async fn silent_books_are_republished_as_stale() {
+        let registry = InstrumentRegistry::with_defaults();
+        let market = Market::parse("BTC/USDT", &registry).unwrap();
+        let views = Arc::new(SummaryViews::new());
+        let callback = Arc::new(BookAggregatorCallback::new(
+            views.clone(),
+            &market,
+            Some(Duration::from_millis(50)),
+        ));
+        callback.watch_staleness();
+        let mut receiver = views
+            .subscribe(SummaryView::all(TEST_BOOKS_SIZE), None)
+            .unwrap()
+            .receiver;
+        callback
+            .accept_book(book(dec!(100), dec!(101)), Exchange::Kraken)
+            .await;
+        assert!(!receiver.borrow_and_update().summary.as_ref().unwrap().bids[0].stale);
+
+        // No further book arrives
+        tokio::time::timeout(Duration::from_secs(1), receiver.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(receiver.borrow_and_update().summary.as_ref().unwrap().bids[0].stale);
+    }
+
+    #[test]
+    fn depth_beyond_inline_levels() {
+        let levels = |first: u32, step: i64| -> BookLevels {
+            (0..30)
+                .map(|i| BookLevel {
+                    price: Decimal::from(first as i64 + step * i),
+                    quantity: dec!(1),
+                })
+                .collect()
+        };
+        let mut aggregator = BookAggregator::new();
+        aggregator.add_new_book(
+            Orderbook::new(levels(1000, -2), levels(2000, 2)),
+            Exchange::Kraken,
+        );
+        aggregator.add_new_book(
+            Orderbook::new(levels(999, -2), levels(2001, 2)),
+            Exchange::Coinbase,
+        );
+        let summary = aggregator.make_summary(50);
+        assert_eq!((summary.bids.len(), summary.asks.len()), (50, 50));
+        assert!(approx_eq!(
+            f64,
+            summary.bids[49].price,
+            951.,
+            epsilon = 0.00001
+        ));
+        assert_eq!(aggregator.make_full_summary().asks.len(), 60);
+        assert_eq!(aggregator.make_summary(1).bids.len(), 1);
+    }
+
     #[test]
     fn make_summary_correctness() {
-        let mut aggregator = BookAggregator::<TEST_BOOKS_SIZE>::new();
+        let mut aggregator = BookAggregator::new();
         let mut summary_correctness_helper = SummaryCorrectness::new();
         let mut rng = rand::thread_rng();
         //
@@ -396,7 +1154,7 @@ mod test {
                 );
                 aggregator.add_new_book(binance_book.clone(), Exchange::Binance);
             }
-            let summary = aggregator.make_summary();
+            let summary = aggregator.make_summary(TEST_BOOKS_SIZE);
             summary_correctness_helper.check_correctness_of_summary(
                 &binance_book,
                 &bitstamp_book,
@@ -0,0 +1,529 @@
+use crate::defines::grpc_scheme::{Level, Summary};
+use crate::defines::Exchange;
+use crate::helper::unix_time_us;
+use crate::marketdata::{summary_of_levels, FullSummary, PricedLevel};
+use halfbrown::HashMap;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tonic::Status;
+
+/// Depth and exchanges of the summary a client subscribed to
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub(crate) struct SummaryView {
+    depth: usize,
+    // Exchanges whose levels are included, in the order of `Exchange::ALL`
+    exchanges: Vec<Exchange>,
+}
+
+impl SummaryView {
+    pub fn new(depth: usize, include: &[Exchange], exclude: &[Exchange]) -> Self {
+        let exchanges = Exchange::ALL
+            .into_iter()
+            .filter(|exchange| include.is_empty() || include.contains(exchange))
+            .filter(|exchange| !exclude.contains(exchange))
+            .collect();
+        Self { depth, exchanges }
+    }
+    /// All exchanges up to `depth`
+    #[cfg(test)]
+    pub fn all(depth: usize) -> Self {
+        Self::new(depth, &[], &[])
+    }
+    /// Selects the view's levels from a summary which contains the levels of all books
+    pub fn select(&self, full: &FullSummary) -> Summary {
+        let select = |levels: &[PricedLevel]| -> Vec<PricedLevel> {
+            levels
+                .iter()
+                .filter(|priced| {
+                    self.exchanges
+                        .iter()
+                        .any(|exchange| exchange.name() == priced.level.exchange)
+                })
+                .take(self.depth)
+                .cloned()
+                .collect()
+        };
+        summary_of_levels(select(&full.bids), select(&full.asks))
+    }
+}
+
+/// Latest summary of a view, clients which are slower than the updates only see the
+/// latest one
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ViewState {
+    pub summary: Option<Arc<Summary>>,
+    // Set once the stream was closed, it is the final message of every client
+    pub closed: Option<Status>,
+}
+
+/// Recent summaries kept for clients which resume a stream after reconnecting
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct ResumeWindow {
+    /// Summaries kept per view, 0 disables resuming
+    pub capacity: usize,
+    /// Views are kept this long after their last client disconnected
+    pub idle_retention: Duration,
+    /// Views without clients which are kept at most, the longest idle ones are dropped first
+    pub max_idle_views: usize,
+}
+
+/// Last summary a client received on a previous stream
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ResumePoint {
+    /// Epoch of the server instance which published the summary
+    pub epoch: u64,
+    pub sequence: u64,
+}
+
+/// Summary stream of a view a client subscribed to
+pub(crate) struct Subscription {
+    pub receiver: watch::Receiver<ViewState>,
+    /// Summary the resuming client received last, followed by the ones it missed. Empty
+    /// unless the client resumed
+    pub resumed: Vec<Arc<Summary>>,
+}
+
+/// Summary streams of a single market. Every distinct view is computed once per update
+/// and shared by all clients which subscribed to it
+pub(crate) struct SummaryViews {
+    views: Mutex<HashMap<SummaryView, ViewStream>>,
+    // Set once the streams were closed, later subscriptions are rejected with it. Locked
+    // after the views
+    closed: Mutex<Option<Status>>,
+    resume_window: ResumeWindow,
+    // Identifies the server instance, clients cannot resume summaries of another one
+    epoch: u64,
+    // Highest sequence of the dropped views, sequences of new views continue after it so
+    // that clients of a dropped view cannot resume from a new one
+    retired_sequence: Mutex<u64>,
+}
+
+struct ViewStream {
+    sender: watch::Sender<ViewState>,
+    // Sequence number of the last published summary
+    sequence: u64,
+    // Latest summaries, oldest first
+    history: VecDeque<Arc<Summary>>,
+    // Time the last client disconnected, `None` while there are clients. Idle views are
+    // not published
+    idle_since: Option<Instant>,
+}
+
+impl SummaryViews {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Self::with_resume_window(ResumeWindow::default(), 1)
+    }
+    /// Views of the server instance `epoch`
+    pub fn with_resume_window(resume_window: ResumeWindow, epoch: u64) -> Self {
+        Self {
+            views: Mutex::new(HashMap::new()),
+            closed: Mutex::new(None),
+            resume_window,
+            epoch,
+            retired_sequence: Mutex::new(0),
+        }
+    }
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+    /// Subscribes to `view`. A client which received the summary of `resume_after` on a
+    /// previous stream of the view gets the summaries it missed since, the subscription
+    /// fails if they are no longer kept or were published by another server instance
+    // tonic::Status is large but it is returned to the gRPC handler as is
+    #[allow(clippy::result_large_err)]
+    pub fn subscribe(
+        &self,
+        view: SummaryView,
+        resume_after: Option<ResumePoint>,
+    ) -> Result<Subscription, Status> {
+        // Checked while holding the views so that `close` cannot run in between
+        let mut views = self.views.lock();
+        if let Some(status) = self.closed.lock().as_ref() {
+            return Err(status.clone());
+        }
+        let resumed = match resume_after {
+            Some(ResumePoint { epoch, .. }) if epoch != self.epoch => {
+                return Err(Status::out_of_range(
+                    "snapshot required, the summaries were published by another server instance",
+                ));
+            }
+            Some(ResumePoint { sequence, .. }) => views
+                .get(&view)
+                .and_then(|stream| stream.resume_after(sequence))
+                .ok_or_else(|| {
+                    Status::out_of_range(format!(
+                        "snapshot required, summaries after {sequence} are no longer available"
+                    ))
+                })?,
+            None => Vec::new(),
+        };
+        let retired_sequence = *self.retired_sequence.lock();
+        let stream = views.entry(view).or_insert_with(|| ViewStream {
+            sender: watch::channel(ViewState::default()).0,
+            sequence: retired_sequence,
+            history: VecDeque::new(),
+            idle_since: None,
+        });
+        stream.idle_since = None;
+        Ok(Subscription {
+            receiver: stream.sender.subscribe(),
+            resumed,
+        })
+    }
+    /// Publishes the views of all subscribed clients, `full` has to contain the levels
+    /// of all aggregated books. Views whose levels did not change are not published
+    pub fn publish(&self, full: &FullSummary) {
+        let mut views = self.views.lock();
+        // Views are dropped once their last client disconnected longer than the retention ago
+        let now = Instant::now();
+        let mut retired_sequence = self.retired_sequence.lock();
+        views.retain(|_, stream| {
+            if stream.sender.receiver_count() > 0 {
+                stream.idle_since = None;
+                return true;
+            }
+            let idle_since = *stream.idle_since.get_or_insert(now);
+            let retained = now - idle_since < self.resume_window.idle_retention;
+            if !retained {
+                *retired_sequence = (*retired_sequence).max(stream.sequence);
+            }
+            retained
+        });
+        let idle = views
+            .values()
+            .filter(|stream| stream.idle_since.is_some())
+            .count();
+        if idle > self.resume_window.max_idle_views {
+            let mut idle_views: Vec<(Instant, SummaryView)> = views
+                .iter()
+                .filter_map(|(view, stream)| Some((stream.idle_since?, view.clone())))
+                .collect();
+            idle_views.sort_unstable_by_key(|(idle_since, _)| *idle_since);
+            for (_, view) in &idle_views[..idle - self.resume_window.max_idle_views] {
+                if let Some(stream) = views.remove(view) {
+                    *retired_sequence = (*retired_sequence).max(stream.sequence);
+                }
+            }
+        }
+        let publish_timestamp_us = unix_time_us();
+        for (view, stream) in views.iter_mut() {
+            if stream.idle_since.is_some() {
+                continue;
+            }
+            let summary = view.select(full);
+            let unchanged = stream
+                .sender
+                .borrow()
+                .summary
+                .as_deref()
+                .is_some_and(|last| same_levels(last, &summary));
+            if unchanged {
+                continue;
+            }
+            stream.sequence += 1;
+            let summary = Arc::new(Summary {
+                sequence: stream.sequence,
+                publish_timestamp_us,
+                epoch: self.epoch,
+                ..summary
+            });
+            if self.resume_window.capacity > 0 {
+                if stream.history.len() == self.resume_window.capacity {
+                    stream.history.pop_front();
+                }
+                stream.history.push_back(summary.clone());
+            }
+            stream
+                .sender
+                .send_modify(|state| state.summary = Some(summary));
+        }
+    }
+    /// Sends `status` as the final message and ends all summary streams
+    pub fn close(&self, status: Status) {
+        let mut views = self.views.lock();
+        *self.closed.lock() = Some(status.clone());
+        for (_, stream) in views.drain() {
+            stream
+                .sender
+                .send_modify(|state| state.closed = Some(status.clone()));
+        }
+    }
+}
+
+impl ViewStream {
+    /// Summary with sequence `sequence` and all later ones, `None` if it is not kept
+    fn resume_after(&self, sequence: u64) -> Option<Vec<Arc<Summary>>> {
+        let oldest = self.history.front()?.sequence;
+        let skip = sequence.checked_sub(oldest)? as usize;
+        (skip < self.history.len()).then(|| self.history.iter().skip(skip).cloned().collect())
+    }
+}
+
+/// Whether both summaries have the same levels, timestamps are ignored
+pub(crate) fn same_levels(a: &Summary, b: &Summary) -> bool {
+    let same = |a: &[Level], b: &[Level]| {
+        a.len() == b.len()
+            && a.iter().zip(b).all(|(a, b)| {
+                a.exchange == b.exchange
+                    && a.price_exact == b.price_exact
+                    && a.amount_exact == b.amount_exact
+                    && a.conversion_rate_exact == b.conversion_rate_exact
+                    && a.stale == b.stale
+            })
+    };
+    same(&a.bids, &b.bids) && same(&a.asks, &b.asks)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::defines::grpc_scheme::Level;
+    use crate::defines::Exchange;
+    use crate::marketdata::{
+        FullSummary, PricedLevel, ResumePoint, ResumeWindow, SummaryView, SummaryViews, ViewState,
+    };
+    use rust_decimal::Decimal;
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    fn level(exchange: Exchange, price: f64) -> PricedLevel {
+        let level = Level {
+            exchange: exchange.name().to_string(),
+            price,
+            amount: 1.,
+            converted: false,
+            conversion_rate: 1.,
+            stale: false,
+            price_exact: price.to_string(),
+            amount_exact: "1".to_string(),
+            conversion_rate_exact: "1".to_string(),
+            exchange_timestamp_us: 0,
+            receive_timestamp_us: 0,
+        };
+        PricedLevel {
+            price: Decimal::try_from(price).unwrap(),
+            level,
+        }
+    }
+
+    fn full_summary() -> FullSummary {
+        FullSummary {
+            bids: vec![
+                level(Exchange::Kraken, 100.),
+                level(Exchange::Binance, 99.),
+                level(Exchange::Kraken, 98.),
+            ],
+            asks: vec![
+                level(Exchange::Binance, 101.),
+                level(Exchange::Kraken, 102.),
+                level(Exchange::Binance, 103.),
+            ],
+        }
+    }
+
+    fn with_best_bid(price: f64) -> FullSummary {
+        let mut summary = full_summary();
+        summary.bids.insert(0, level(Exchange::Bitstamp, price));
+        summary
+    }
+
+    #[test]
+    fn views_filter_and_truncate() {
+        let top = SummaryView::all(1).select(&full_summary());
+        assert_eq!((top.bids.len(), top.asks.len()), (1, 1));
+        assert_eq!(top.spread, 1.);
+
+        let binance = SummaryView::new(10, &[Exchange::Binance], &[]).select(&full_summary());
+        assert!(binance
+            .bids
+            .iter()
+            .chain(binance.asks.iter())
+            .all(|level| level.exchange == Exchange::Binance.name()));
+        assert_eq!((binance.bids.len(), binance.asks.len()), (1, 2));
+        assert_eq!(binance.spread, 2.);
+        assert_eq!(binance.spread_exact, "2");
+
+        assert_eq!(
+            SummaryView::new(10, &[], &[Exchange::Kraken]),
+            SummaryView::new(
+                10,
+                &[Exchange::Binance, Exchange::Bitstamp, Exchange::Coinbase],
+                &[]
+            )
+        );
+    }
+
+    #[test]
+    fn clients_of_a_view_share_its_stream() {
+        let views = SummaryViews::new();
+        let mut first = views.subscribe(SummaryView::all(1), None).unwrap().receiver;
+        let mut second = views.subscribe(SummaryView::all(1), None).unwrap().receiver;
+        let mut ladder = views
+            .subscribe(SummaryView::all(10), None)
+            .unwrap()
+            .receiver;
+        assert_eq!(views.views.lock().len(), 2);
+
+        views.publish(&full_summary());
+        let latest = |receiver: &mut watch::Receiver<ViewState>| {
+            assert!(receiver.has_changed().unwrap());
+            receiver.borrow_and_update().summary.clone().unwrap()
+        };
+        assert_eq!(latest(&mut first).bids.len(), 1);
+        assert_eq!(latest(&mut second).bids.len(), 1);
+        let summary = latest(&mut ladder);
+        assert_eq!(summary.bids.len(), 3);
+        assert_eq!(summary.sequence, 1);
+        assert_ne!(summary.publish_timestamp_us, 0);
+
+        drop(ladder);
+        // Slow clients only see the latest summary
+        views.publish(&with_best_bid(100.5));
+        views.publish(&full_summary());
+        assert_eq!(latest(&mut first).sequence, 3);
+        assert_eq!(views.views.lock().len(), 1);
+
+        views.close(tonic::Status::unavailable("closed"));
+        assert!(first.borrow().closed.is_some());
+        assert!(views.subscribe(SummaryView::all(1), None).is_err());
+    }
+
+    #[test]
+    fn unchanged_views_are_not_published() {
+        let views = SummaryViews::new();
+        let mut top = views.subscribe(SummaryView::all(1), None).unwrap().receiver;
+        let mut ladder = views
+            .subscribe(SummaryView::all(10), None)
+            .unwrap()
+            .receiver;
+        views.publish(&full_summary());
+        top.borrow_and_update();
+        ladder.borrow_and_update();
+
+        // Only timestamps changed
+        let mut refreshed = full_summary();
+        refreshed.bids[0].level.receive_timestamp_us = 1;
+        views.publish(&refreshed);
+        assert!(!top.has_changed().unwrap());
+        assert!(!ladder.has_changed().unwrap());
+
+        // Change beyond the top level
+        let mut deeper = full_summary();
+        deeper.bids[2].level.amount_exact = "2".to_string();
+        views.publish(&deeper);
+        assert!(!top.has_changed().unwrap());
+        assert_eq!(
+            ladder
+                .borrow_and_update()
+                .summary
+                .as_ref()
+                .unwrap()
+                .sequence,
+            2
+        );
+    }
+
+    #[test]
+    fn missed_summaries_are_resumed() {
+        let window = ResumeWindow {
+            capacity: 2,
+            idle_retention: Duration::from_secs(60),
+            max_idle_views: 1,
+        };
+        let views = SummaryViews::with_resume_window(window, 7);
+        let receiver = views.subscribe(SummaryView::all(1), None).unwrap().receiver;
+        for price in [100.1, 100.2, 100.3] {
+            views.publish(&with_best_bid(price));
+        }
+        let resume = |view: SummaryView, epoch, sequence| {
+            views
+                .subscribe(view, Some(ResumePoint { epoch, sequence }))
+                .map(|subscription| {
+                    subscription
+                        .resumed
+                        .iter()
+                        .map(|summary| summary.sequence)
+                        .collect::<Vec<_>>()
+                })
+                .map_err(|status| status.code())
+        };
+        let resumed = |sequence| resume(SummaryView::all(1), 7, sequence);
+        assert_eq!(resumed(2).unwrap(), vec![2, 3]);
+        assert_eq!(resumed(3).unwrap(), vec![3]);
+        // Outside of the window
+        assert_eq!(resumed(1), Err(tonic::Code::OutOfRange));
+        assert!(resumed(4).is_err());
+        // Summaries of another server instance
+        assert_eq!(
+            resume(SummaryView::all(1), 6, 3),
+            Err(tonic::Code::OutOfRange)
+        );
+        // Resuming an unknown view does not create it
+        assert!(resume(SummaryView::all(2), 7, 3).is_err());
+        assert_eq!(views.views.lock().len(), 1);
+
+        // Views are kept for clients which reconnect, but not published without clients
+        drop(receiver);
+        views.publish(&with_best_bid(100.4));
+        assert_eq!(resumed(3).unwrap(), vec![3]);
+        let mut receiver = views
+            .subscribe(
+                SummaryView::all(1),
+                Some(ResumePoint {
+                    epoch: 7,
+                    sequence: 3,
+                }),
+            )
+            .unwrap()
+            .receiver;
+        views.publish(&with_best_bid(100.5));
+        let summary = receiver.borrow_and_update().summary.clone().unwrap();
+        assert_eq!((summary.sequence, summary.epoch), (4, 7));
+
+        // Only the most recently idle views are kept
+        let ladder = views.subscribe(SummaryView::all(10), None).unwrap();
+        views.publish(&full_summary());
+        drop(receiver);
+        views.publish(&full_summary());
+        drop(ladder);
+        views.publish(&full_summary());
+        assert_eq!(views.views.lock().len(), 1);
+        assert!(resumed(4).is_err());
+
+        // Sequences of a dropped view are not reused
+        let views = SummaryViews::with_resume_window(
+            ResumeWindow {
+                idle_retention: Duration::ZERO,
+                ..window
+            },
+            7,
+        );
+        let receiver = views.subscribe(SummaryView::all(1), None).unwrap().receiver;
+        views.publish(&with_best_bid(100.1));
+        drop(receiver);
+        views.publish(&with_best_bid(100.2));
+        let mut receiver = views.subscribe(SummaryView::all(1), None).unwrap().receiver;
+        assert!(views
+            .subscribe(
+                SummaryView::all(1),
+                Some(ResumePoint {
+                    epoch: 7,
+                    sequence: 1
+                })
+            )
+            .is_err());
+        views.publish(&full_summary());
+        assert_eq!(
+            receiver
+                .borrow_and_update()
+                .summary
+                .as_ref()
+                .unwrap()
+                .sequence,
+            2
+        );
+    }
+}